@@ -125,16 +125,6 @@ function start_services() {
     done
 }
 
-function define_vm_network() {
-    echo "Creating isolated libvirt network..."
-
-    virsh net-destroy ovn-ci-isolated || true
-    virsh net-undefine ovn-ci-isolated || true
-    virsh net-define vm/network.xml
-    virsh net-start ovn-ci-isolated
-    virsh net-autostart ovn-ci-isolated
-}
-
 install_dependencies
 setup_workspace
 clone_repo ovn $OVN_REPO $OVN_BRANCH
@@ -152,4 +142,3 @@ setup_selinux
 create_ssh_key
 configure_modular_libvirt
 start_services
-define_vm_network
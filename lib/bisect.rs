@@ -0,0 +1,404 @@
+use std::fs;
+use std::fs::DirBuilder;
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use thiserror::Error as ThisError;
+
+use crate::config::{Configuration, Suite};
+use crate::git::{Error as GitError, Git};
+use crate::runner::{Error as RunnerError, Finished, Runner, Running};
+use crate::testsuite::select_keywords;
+use crate::vm::SlotPool;
+use crate::xml::escape;
+
+const BISECT_DIR: &str = "bisect";
+/// The worktrees replace "/workspace/ovn" in the guest.
+const WORKTREE_NAME: &str = "ovn";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Git(#[from] GitError),
+    #[error("Cannot create bisect directory: {0}")]
+    Directory(#[source] IoError),
+    #[error("Cannot write bisect report: {0}")]
+    Report(#[source] IoError),
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    /// The first bad commit with its subject.
+    Found(String, String),
+    /// No steps left, the first bad commit is after the good one up
+    /// to the bad one.
+    Range(String, String),
+    /// The job couldn't be run at all, e.g. the VM didn't start.
+    Aborted(String),
+}
+
+/// Job run on a single commit.
+#[derive(Debug)]
+struct Step {
+    commit: String,
+    bad: bool,
+    log_path: PathBuf,
+}
+
+/// Bisection of a single job.
+#[derive(Debug)]
+pub struct Bisection {
+    job: String,
+    steps: Vec<Step>,
+    outcome: Outcome,
+}
+
+impl Bisection {
+    pub fn summary(&self) -> String {
+        match &self.outcome {
+            Outcome::Found(commit, subject) => {
+                format!(
+                    "{}: first bad commit {} \"{subject}\"",
+                    self.job,
+                    short(commit)
+                )
+            }
+            Outcome::Range(good, bad) => format!(
+                "{}: first bad commit within {}..{}",
+                self.job,
+                short(good),
+                short(bad)
+            ),
+            Outcome::Aborted(reason) => format!("{}: bisect aborted, {reason}", self.job),
+        }
+    }
+
+    fn report_html(&self, host: &str, log_prefix: &str) -> String {
+        let steps = self
+            .steps
+            .iter()
+            .map(|step| {
+                format!(
+                    r#"<a href="{}" target="_blank">{}</a> <a href="http://{}:8080/{}/ovn-ci.log" target="_blank">{}</a>"#,
+                    commit_url(&step.commit),
+                    short(&step.commit),
+                    host,
+                    step.log_path
+                        .strip_prefix(log_prefix)
+                        .unwrap_or(Path::new(""))
+                        .to_string_lossy(),
+                    if step.bad { "bad" } else { "good" }
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("<br>");
+
+        let (class, result) = match &self.outcome {
+            Outcome::Found(commit, subject) => (
+                "fail",
+                format!(
+                    r#"<a href="{}" target="_blank">{}</a> {}"#,
+                    commit_url(commit),
+                    short(commit),
+                    escape(subject)
+                ),
+            ),
+            Outcome::Range(good, bad) => ("", format!("Within {}..{}", short(good), short(bad))),
+            Outcome::Aborted(reason) => ("", format!("Aborted: {}", escape(reason))),
+        };
+
+        format!(
+            r#"<tr><td>{}</td><td class="{class}">{result}</td><td>{}</td></tr>"#,
+            escape(&self.job),
+            if steps.is_empty() { "-" } else { &steps }
+        )
+    }
+}
+
+/// Bisection in progress, the steps of the jobs run in parallel as
+/// long as there are free slots.
+struct Job {
+    /// The suite narrowed to the target tests.
+    suite: Suite,
+    /// Names of the tests whose failure makes a commit bad, any failure
+    /// of the job if empty.
+    tests: Vec<String>,
+    worktree: String,
+    log_path: PathBuf,
+    /// Oldest commit that can still be the first bad one.
+    first: usize,
+    /// Oldest commit known to be bad.
+    bad: usize,
+    running: Option<(String, Runner<Running>)>,
+    bisection: Bisection,
+    done: bool,
+}
+
+/// Reruns jobs on the OVN commits between a good and a bad run, the
+/// commits are checked out in a worktree per job so the main checkout
+/// stays untouched. OVS stays at the version of the base image.
+pub struct Bisect<'a> {
+    config: &'a Configuration,
+    slots: SlotPool,
+    log_path: PathBuf,
+    max_steps: usize,
+}
+
+impl<'a> Bisect<'a> {
+    pub fn new(
+        config: &'a Configuration,
+        slots: SlotPool,
+        log_path: &Path,
+        max_steps: usize,
+    ) -> Result<Self> {
+        let log_path = log_path.join(BISECT_DIR);
+        DirBuilder::new()
+            .recursive(true)
+            .create(&log_path)
+            .map_err(Error::Directory)?;
+
+        Ok(Bisect {
+            config,
+            slots,
+            log_path,
+            max_steps,
+        })
+    }
+
+    /// Finds the first commit after `good` up to `bad` that fails
+    /// each of the suites, only the named tests of a suite run if there
+    /// are any.
+    pub fn run(
+        &self,
+        suites: Vec<(Suite, Vec<String>)>,
+        good: &str,
+        bad: &str,
+    ) -> Result<Vec<Bisection>> {
+        let git = Git::new(self.config.git().ovn_path());
+        // Oldest first, the last one is known to be bad.
+        let commits = git.rev_list(good, bad)?;
+
+        let mut jobs = suites
+            .into_iter()
+            .enumerate()
+            .map(|(index, (suite, tests))| self.job(&git, suite, tests, index, &commits, good))
+            .collect::<Vec<_>>();
+
+        while jobs.iter().any(|job| !job.done) {
+            for job in jobs.iter_mut().filter(|job| !job.done) {
+                self.advance(job, &commits, good);
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let mut bisections = Vec::with_capacity(jobs.len());
+        for mut job in jobs {
+            if Path::new(&job.worktree).exists()
+                && let Err(e) = git.worktree_remove(&job.worktree)
+            {
+                eprintln!("Couldn't remove bisect worktree {}: {e}", job.worktree);
+            }
+
+            if let Outcome::Found(commit, subject) = &mut job.bisection.outcome {
+                *subject = git.subject(commit)?;
+            }
+            bisections.push(job.bisection);
+        }
+
+        Ok(bisections)
+    }
+
+    fn job(
+        &self,
+        git: &Git,
+        suite: Suite,
+        tests: Vec<String>,
+        index: usize,
+        commits: &[String],
+        good: &str,
+    ) -> Job {
+        let log_path = self.log_path.join(index.to_string());
+        let name = suite.name();
+        let suite = if tests.is_empty() {
+            suite
+        } else {
+            suite.with_test_range(select_keywords(&tests))
+        };
+
+        let mut job = Job {
+            worktree: log_path.join(WORKTREE_NAME).to_string_lossy().to_string(),
+            log_path,
+            first: 0,
+            bad: commits.len().saturating_sub(1),
+            running: None,
+            bisection: Bisection {
+                job: name,
+                steps: Vec::new(),
+                outcome: Outcome::Aborted(format!("no commits after {}", short(good))),
+            },
+            suite,
+            tests,
+            done: commits.is_empty(),
+        };
+
+        if let Some(bad) = commits.last()
+            && let Err(e) = git.worktree_add(&job.worktree, bad)
+        {
+            job.bisection.outcome = Outcome::Aborted(e.to_string());
+            job.done = true;
+        }
+
+        job
+    }
+
+    /// Collects the finished step of the job and starts the next one.
+    fn advance(&self, job: &mut Job, commits: &[String], good: &str) {
+        if let Some((commit, mut runner)) = job.running.take() {
+            if !runner.try_ready() {
+                job.running = Some((commit, runner));
+                return;
+            }
+
+            let runner = runner.finish();
+            println!("{}", runner.report_console());
+            if let Err(reason) = self.record_step(job, commit, runner) {
+                job.bisection.outcome = Outcome::Aborted(reason);
+                job.done = true;
+                return;
+            }
+        }
+
+        if job.first >= job.bad {
+            job.bisection.outcome = Outcome::Found(commits[job.bad].clone(), String::new());
+            job.done = true;
+            return;
+        }
+
+        if job.bisection.steps.len() >= self.max_steps {
+            let good = job
+                .first
+                .checked_sub(1)
+                .map_or(good, |index| &commits[index]);
+            job.bisection.outcome = Outcome::Range(good.to_string(), commits[job.bad].clone());
+            job.done = true;
+            return;
+        }
+
+        if self.slots.available() < job.suite.nodes() {
+            return;
+        }
+
+        let commit = &commits[(job.first + job.bad) / 2];
+        match self.start_step(job, commit) {
+            Ok(runner) => job.running = Some((commit.clone(), runner)),
+            Err(reason) => {
+                job.bisection.outcome = Outcome::Aborted(reason);
+                job.done = true;
+            }
+        }
+    }
+
+    fn start_step(&self, job: &Job, commit: &str) -> std::result::Result<Runner<Running>, String> {
+        Git::new(&job.worktree)
+            .checkout(commit)
+            .map_err(|e| e.to_string())?;
+
+        let log_path = job.log_path.join(short(commit));
+        DirBuilder::new()
+            .recursive(true)
+            .create(&log_path)
+            .map_err(|e| e.to_string())?;
+
+        let runner = Runner::new(
+            self.config.vm(),
+            self.config.jobs(),
+            self.config.timeout(),
+            &job.suite,
+            &log_path,
+            self.slots.clone(),
+            self.config.keep_failed_ttl(),
+        )
+        .with_source(&job.worktree);
+        println!("{}", runner.report_console());
+
+        runner.run().map_err(|runner| {
+            println!("{}", runner.report_console());
+            format!(
+                "commit {}: {}",
+                short(commit),
+                runner.error().map_or(String::new(), |e| e.to_string())
+            )
+        })
+    }
+
+    fn record_step(
+        &self,
+        job: &mut Job,
+        commit: String,
+        runner: Runner<Finished>,
+    ) -> std::result::Result<(), String> {
+        let bad = match runner.error() {
+            None => false,
+            Some(RunnerError::ReturnCode(_)) if job.tests.is_empty() => true,
+            Some(RunnerError::ReturnCode(_)) if runner.tests().is_empty() => {
+                return Err(format!("commit {}: no test results", short(&commit)));
+            }
+            Some(RunnerError::ReturnCode(_)) => runner
+                .failed_tests()
+                .any(|case| job.tests.iter().any(|test| test == case.name())),
+            Some(e) => return Err(format!("commit {}: {e}", short(&commit))),
+        };
+
+        let middle = (job.first + job.bad) / 2;
+        if bad {
+            job.bad = middle;
+        } else {
+            job.first = middle + 1;
+        }
+
+        job.bisection.steps.push(Step {
+            commit,
+            bad,
+            log_path: runner.log_path().to_path_buf(),
+        });
+
+        Ok(())
+    }
+}
+
+/// Saves "bisect.html" into the run log directory.
+pub fn save_report(
+    log_path: &Path,
+    header: &str,
+    bisections: &[Bisection],
+    host: &str,
+    log_prefix: &str,
+) -> Result<PathBuf> {
+    let rows = bisections
+        .iter()
+        .map(|bisection| bisection.report_html(host, log_prefix))
+        .collect::<String>();
+
+    let report = include_str!("../template/bisect.html")
+        .replace("@HEADER@", header)
+        .replace("@ROWS@", &rows);
+
+    let path = log_path.join("bisect.html");
+    fs::write(&path, report).map_err(Error::Report)?;
+
+    Ok(path)
+}
+
+fn short(commit: &str) -> &str {
+    commit.get(..12).unwrap_or(commit)
+}
+
+fn commit_url(commit: &str) -> String {
+    format!("https://github.com/ovn-org/ovn/commit/{commit}")
+}
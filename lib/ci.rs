@@ -5,19 +5,29 @@ use std::os::unix;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use chrono::{DateTime, Datelike};
+use chrono::DateTime;
 use thiserror::Error as ThisError;
 
+use crate::bisect::{Bisect, Error as BisectError, save_report as save_bisect_report};
 use crate::cli_report::CliReport;
-use crate::config::Configuration;
+use crate::config::{Configuration, FlakyList, Image, Suite};
 use crate::email::{Error as EmailError, Report as EmailReport};
+use crate::flaky::FlakeRates;
 use crate::git::{Error as GitError, Git};
+use crate::history::{Error as HistoryError, History, Result as HistoryResult};
 use crate::ignore_not_found;
+use crate::junit;
+use crate::regression::{HISTORY_WINDOW, Regressions, RunStatus};
+use crate::results::{ImageStatus, Results, Run};
+use crate::runner::{Error as RunnerError, Finished, Runner};
 use crate::scheduler::Scheduler;
+use crate::testsuite::{select_keywords, skip_keywords};
 use crate::util::Arch;
-use crate::vm::{BaseVm, BaseVmError};
-
-const BUILD_AT_DAY: u32 = 1;
+use crate::vm::{
+    AddressPool, BaseVm, BaseVmError, ImageError, Network, NetworkError, PackageDiff,
+    RebuildReason, SlotPool, SmokeFailures, base_image_log_path, reap_kept, rebuild_decision,
+};
+use crate::xml::escape;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -27,14 +37,26 @@ pub enum Error {
     Git(#[from] GitError),
     #[error("Base VM error: {0}")]
     BaseVm(#[from] BaseVmError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
     #[error("Cannot create log directory structure: {0}")]
     LogDirectory(#[source] IoError),
     #[error("At least one job failed")]
     Failure,
     #[error("Cannot create HTML report: {0}")]
     HtmlReport(#[source] IoError),
+    #[error("Cannot save results: {0}")]
+    Results(#[source] IoError),
+    #[error("Cannot record run history: {0}")]
+    History(#[from] HistoryError),
+    #[error("Bisect failed: {0}")]
+    Bisect(#[from] BisectError),
     #[error("Cannot send email report: {0}")]
     EmailReport(#[from] EmailError),
+    #[error("No free VM slot for the base image smoke test")]
+    NoSlot,
+    #[error("Pre-flight check of {0} base image failed, no job was started: {1}")]
+    Preflight(&'static str, #[source] BaseVmError),
 }
 
 macro_rules! _push_finished_and_report {
@@ -50,10 +72,14 @@ pub struct ContinuousIntegration {
     build_image: bool,
     scheduler: Scheduler,
     reporting: Option<CliReport>,
+    slots: SlotPool,
+    images: Vec<ImageStatus>,
+    packages: Vec<PackageDiff>,
+    start: SystemTime,
 }
 
 impl ContinuousIntegration {
-    pub fn new(config: Configuration, build_image: bool) -> Self {
+    pub fn new(config: Configuration, build_image: bool, keep_on_failure: bool) -> Self {
         let (log_path, log_name) = create_log_path(config.log_path());
         let reporting = config.cli_report_binary().map(|bin| {
             CliReport::new(
@@ -61,7 +87,17 @@ impl ContinuousIntegration {
                 format!("http://{}:8080/{}", config.host(), log_name),
             )
         });
-        let scheduler = Scheduler::new(&config, &log_path, reporting.clone());
+        let addresses = AddressPool::new(config.vm().network());
+        // VMs kept from earlier runs occupy their slots until they expire.
+        let kept = reap_kept();
+        let keep_failed = config.keep_failed().max(keep_on_failure as usize);
+        let slots = SlotPool::new(
+            config.concurrent_limit() + keep_failed,
+            &addresses,
+            keep_failed.saturating_sub(kept.len()),
+            &kept,
+        );
+        let scheduler = Scheduler::new(&config, &log_path, reporting.clone(), slots.clone());
 
         ContinuousIntegration {
             config,
@@ -69,6 +105,10 @@ impl ContinuousIntegration {
             build_image,
             scheduler,
             reporting,
+            slots,
+            images: Vec::new(),
+            packages: Vec::new(),
+            start: SystemTime::now(),
         }
     }
 
@@ -93,17 +133,58 @@ impl ContinuousIntegration {
     }
 
     fn run_inner(&mut self) -> Result<()> {
+        // The image update boots the smoke test VMs on the network.
+        Network::new(self.config.vm().network()).ensure()?;
         self.update()?;
 
+        // The jobs run without the history, only the comparisons are missing.
+        let mut history = History::open(&self.config.history_path())
+            .inspect_err(|e| eprintln!("Couldn't open run history: {e}"))
+            .ok();
+        let flaky = self.flaky_tests(history.as_ref());
+
         self.scheduler.run();
 
-        let header = self.report_header();
-        let report_path = self.save_html_report(&self.log_path, &header)?;
+        let arch = Arch::get();
+        let previous = query_history(history.as_ref(), |h| h.recent(arch.name(), HISTORY_WINDOW));
+        let previous_hash = query_history(history.as_ref(), |h| h.last_ovn_hash(arch.name()));
+        let regressions = Regressions::new(
+            &RunStatus::from_runners(self.scheduler.finished()),
+            &previous,
+        );
+
+        let header = self.report_header(&regressions);
+        let report_path = self.save_html_report(&self.log_path, &header, &regressions, &flaky)?;
+        if let Err(e) = self.save_results(&self.log_path) {
+            eprintln!("Couldn't save results: {e}");
+        }
+        if let Err(e) = junit::save_run(&self.log_path, self.scheduler.finished()) {
+            eprintln!("Couldn't save JUnit report: {e}");
+        }
+        if let Some(history) = history.as_mut()
+            && let Err(e) = self.record_history(history)
+        {
+            eprintln!("Couldn't record run history: {e}");
+        }
         self.create_latest_symlink()?;
 
-        if self.should_fail() {
-            if let Some(email) = self.config.email() {
-                EmailReport::new(email, &report_path, &header, self.config.host())?.send()?;
+        let failed = self.should_fail();
+        // A fixed job is a change worth the report even when all passed.
+        if let Some(email) = self.config.email()
+            && if email.only_on_change() {
+                regressions.has_changes()
+            } else {
+                failed
+            }
+        {
+            EmailReport::new(email, &report_path, &header, self.config.host())?.send()?;
+        }
+
+        if failed {
+            if let (Some(status), Some(hash)) = (previous.first(), previous_hash)
+                && let Err(e) = self.bisect(&hash, status, &regressions)
+            {
+                eprintln!("Couldn't bisect the new failures: {e}");
             }
 
             return Err(Error::Failure);
@@ -112,6 +193,116 @@ impl ContinuousIntegration {
         Ok(())
     }
 
+    /// Flake rates from the previous runs, the jobs skip their flaky
+    /// tests if the list is enabled and the recheck jobs run them.
+    fn flaky_tests(&mut self, history: Option<&History>) -> FlakeRates {
+        let config = self.config.flaky();
+        let runs = query_history(history, |h| h.recent(Arch::get().name(), config.runs()));
+        let flaky = FlakeRates::new(&runs, config.min_rate());
+
+        let Some(list) = config.list() else {
+            return flaky;
+        };
+
+        let mut rechecks = Vec::new();
+        for runner in self.scheduler.waiting_mut() {
+            // Autotest can't exclude tests from a range, rechecking
+            // suites rerun all their failures already.
+            if runner.suite().has_test_range() || runner.suite().is_recheck() {
+                continue;
+            }
+
+            // The tests run by the recheck job are recorded under its name.
+            let suite = runner.suite().with_recheck();
+            let mut tests = flaky.tests(runner.name());
+            tests.extend(flaky.tests(&suite.name()));
+            tests.sort();
+            tests.dedup();
+
+            if tests.is_empty() {
+                continue;
+            }
+
+            runner.set_test_keywords(skip_keywords(&tests));
+
+            if list == FlakyList::Recheck {
+                let mut recheck = Runner::new(
+                    self.config.vm(),
+                    self.config.jobs(),
+                    self.config.timeout(),
+                    &suite,
+                    &self.log_path,
+                    self.slots.clone(),
+                    self.config.keep_failed_ttl(),
+                );
+                recheck.set_test_keywords(select_keywords(&tests));
+                rechecks.push(recheck);
+            }
+        }
+
+        for recheck in rechecks {
+            self.scheduler.add(recheck);
+        }
+
+        flaky
+    }
+
+    /// Bisects the jobs whose tests failed while they passed in the
+    /// previous run, narrowed to the newly failing tests. The result is
+    /// sent as a follow-up email.
+    fn bisect(&self, good: &str, previous: &RunStatus, regressions: &Regressions) -> Result<()> {
+        let Some(config) = self.config.bisect() else {
+            return Ok(());
+        };
+
+        let (bad, _) = self.commit_hashes()?;
+        let runners = self
+            .scheduler
+            .finished()
+            .filter(|runner| matches!(runner.error(), Some(RunnerError::ReturnCode(_))))
+            .filter(|runner| previous.job_failed(runner.name()) == Some(false))
+            .collect::<Vec<_>>();
+
+        if good == bad || runners.is_empty() {
+            return Ok(());
+        }
+
+        let bisect = Bisect::new(
+            &self.config,
+            self.slots.clone(),
+            &self.log_path,
+            config.max_steps(),
+        )?;
+        let suites = runners
+            .iter()
+            .map(|runner| bisect_suite(runner, regressions))
+            .collect();
+        let bisections = bisect.run(suites, good, &bad)?;
+
+        for bisection in &bisections {
+            println!("{}", bisection.summary());
+        }
+
+        let header = format!(
+            "OVN CI - Bisect - {} - {}",
+            DateTime::from(SystemTime::now()).format("%d %B %Y"),
+            Arch::get().name()
+        );
+        let report_path = save_bisect_report(
+            &self.log_path,
+            &header,
+            &bisections,
+            self.config.host(),
+            self.config.log_path(),
+        )?;
+
+        if let Some(email) = self.config.email() {
+            EmailReport::new(email, &report_path, &header, self.config.host())?.send()?;
+        }
+
+        Ok(())
+    }
+
     fn create_log_directory(&self) -> Result<()> {
         DirBuilder::new()
             .recursive(true)
@@ -120,17 +311,148 @@ impl ContinuousIntegration {
     }
 
     fn update(&mut self) -> Result<()> {
-        let date = DateTime::from(SystemTime::now());
-        let mut vm = BaseVm::new(&self.config, &self.log_path);
+        let mut images = Vec::new();
+        let mut packages = Vec::new();
+        // The symlink still points to the previous run.
+        let previous_run = PathBuf::from(self.config.log_path()).join("latest");
+
+        for image in self.config.vm().images() {
+            let distro = image.distro();
+            let mut vm = BaseVm::new(&self.config, &image, &self.log_path);
+            let current = vm.current_version();
+            let reason = rebuild_decision(
+                self.config.vm().rebuild(),
+                &image,
+                current.as_ref(),
+                self.build_image,
+            );
+
+            let mut decision = match (&reason, current.as_ref()) {
+                (Some(reason), _) => {
+                    println!("Creating new {} base image, {reason}.", distro.as_name());
+                    format!("rebuilt, {reason}")
+                }
+                (None, Some(current)) => {
+                    println!("Keeping {} base image.", current.describe());
+                    "kept".to_string()
+                }
+                (None, None) => {
+                    return Err(Error::BaseVm(
+                        ImageError::NoCurrent(distro.as_name()).into(),
+                    ));
+                }
+            };
+
+            let mut updated = false;
+            if let Some(reason) = reason {
+                match self.rebuild_image(&mut vm, &image) {
+                    Ok(()) => updated = true,
+                    Err(e) if current.is_some() => {
+                        eprintln!(
+                            "Couldn't create new {} base image, keeping the current one: {e}",
+                            distro.as_name()
+                        );
+                        vm.discard();
+                        decision = format!("rebuild failed, {reason}");
+                    }
+                    Err(e) => {
+                        vm.discard();
+                        return Err(e);
+                    }
+                }
+            }
+
+            if !updated {
+                println!("Updating {} base image.", distro.as_name());
+                vm.copy_current()?;
+                if let Err(e) = vm.update() {
+                    vm.discard();
+                    return Err(e.into());
+                }
+
+                match self.smoke_test(&mut vm, &image) {
+                    Ok(()) => {
+                        vm.promote()?;
+                    }
+                    Err(e) => {
+                        vm.discard();
+                        decision = self.smoke_failed(&mut vm, &image, e)?;
+                    }
+                }
+            }
+
+            if let Some(version) = vm.current_version() {
+                images.push(ImageStatus::new(version, decision));
+            }
 
-        if self.build_image || date.day() == BUILD_AT_DAY {
-            println!("Creating new base image.");
-            vm.rebuild()?;
+            let diff = PackageDiff::new(
+                distro,
+                &base_image_log_path(&previous_run, distro),
+                &base_image_log_path(&self.log_path, distro),
+            );
+            if !diff.is_empty() {
+                println!("Packages of {} base image changed.", distro.as_name());
+            }
+            packages.push(diff);
         }
 
-        println!("Updating base image.");
+        self.images = images;
+        self.packages = packages;
+
+        Ok(())
+    }
+
+    /// Rebuilds the image right away when the smoke test of the current
+    /// image failed too many times in a row, fails the run otherwise.
+    fn smoke_failed(&self, vm: &mut BaseVm, image: &Image, error: Error) -> Result<String> {
+        let failures = SmokeFailures::new(image.distro()).record();
+        let limit = self.config.vm().rebuild().smoke_failures();
+
+        if limit.is_none_or(|limit| failures < limit) {
+            return Err(error);
+        }
+
+        let reason = RebuildReason::SmokeFailures(failures);
+        println!(
+            "Creating new {} base image, {reason}.",
+            image.distro().as_name()
+        );
+
+        if let Err(e) = self.rebuild_image(vm, image) {
+            eprintln!("Couldn't create new base image: {e}");
+            vm.discard();
+            return Err(error);
+        }
+
+        Ok(format!("rebuilt, {reason}"))
+    }
+
+    /// Builds, updates and smoke tests new image version, it's
+    /// promoted only when all of them pass.
+    fn rebuild_image(&self, vm: &mut BaseVm, image: &Image) -> Result<()> {
+        vm.rebuild()?;
         vm.update()?;
 
+        self.smoke_test(vm, image)?;
+
+        if let Some(version) = vm.promote()? {
+            println!("Promoted base image {}.", version.describe());
+        }
+
+        Ok(())
+    }
+
+    fn smoke_test(&self, vm: &mut BaseVm, image: &Image) -> Result<()> {
+        println!(
+            "Running {} base image smoke test.",
+            image.distro().as_name()
+        );
+        let slot = self.slots.acquire().ok_or(Error::NoSlot)?;
+
+        vm.smoke_test(slot)
+            .map_err(|e| Error::Preflight(image.distro().as_name(), e))?;
+        SmokeFailures::new(image.distro()).reset();
+
         Ok(())
     }
 
@@ -149,20 +471,67 @@ impl ContinuousIntegration {
         self.scheduler.finished().any(|runner| !runner.success())
     }
 
-    fn save_html_report(&self, log_path: &Path, header: &str) -> Result<PathBuf> {
+    fn commit_hashes(&self) -> Result<(String, String)> {
         let git_config = self.config.git();
         let ovn_hash = Git::new(git_config.ovn_path()).commit_hash()?;
         let ovs_hash = Git::new(git_config.ovs_path()).commit_hash()?;
+
+        Ok((ovn_hash, ovs_hash))
+    }
+
+    fn save_html_report(
+        &self,
+        log_path: &Path,
+        header: &str,
+        regressions: &Regressions,
+        flaky: &FlakeRates,
+    ) -> Result<PathBuf> {
+        let (ovn_hash, ovs_hash) = self.commit_hashes()?;
         let mut template = include_str!("../template/report.html").to_string();
 
         let rows = self
             .scheduler
             .finished()
-            .map(|r| r.report_html(self.config.host(), self.config.log_path()))
+            .map(|r| r.report_html(self.config.host(), self.config.log_path(), flaky))
+            .collect::<String>();
+
+        let failures = self
+            .scheduler
+            .finished()
+            .filter_map(|r| {
+                r.failed_tests_summary()
+                    .map(|summary| format!("<li>{}: {}</li>", r.name(), escape(&summary)))
+            })
             .collect::<String>();
 
         template = template.replace("@ROWS@", &rows);
+        template = template.replace(
+            "@FAILURES@",
+            &if failures.is_empty() {
+                String::new()
+            } else {
+                format!("<h4>Failed tests</h4><ul>{failures}</ul>")
+            },
+        );
         template = template.replace("@HEADER@", header);
+        template = template.replace("@CHANGES@", &regressions.report_html());
+        template = template.replace(
+            "@IMAGES@",
+            &self
+                .images
+                .iter()
+                .map(ImageStatus::describe)
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        template = template.replace(
+            "@PACKAGES@",
+            &self
+                .packages
+                .iter()
+                .map(|diff| diff.report_html())
+                .collect::<String>(),
+        );
         template = template.replace("@OVN_HASH@", &ovn_hash);
         template = template.replace("@OVN_HASH_SHORT@", &ovn_hash[..12]);
         template = template.replace("@OVS_HASH@", &ovs_hash);
@@ -179,6 +548,26 @@ impl ContinuousIntegration {
         Ok(path)
     }
 
+    fn save_results(&self, log_path: &Path) -> Result<()> {
+        let (ovn_hash, ovs_hash) = self.commit_hashes()?;
+        let arch = Arch::get();
+        let run = Run::new(&ovn_hash, &ovs_hash, arch.name(), self.start, &self.images);
+
+        Results::new(run, self.scheduler.finished(), log_path)
+            .save(log_path)
+            .map_err(Error::Results)
+    }
+
+    fn record_history(&self, history: &mut History) -> Result<()> {
+        let (ovn_hash, ovs_hash) = self.commit_hashes()?;
+        let arch = Arch::get();
+        let run = Run::new(&ovn_hash, &ovs_hash, arch.name(), self.start, &self.images);
+
+        history.record(&run, &self.log_path, self.scheduler.finished())?;
+
+        Ok(())
+    }
+
     fn create_latest_symlink(&self) -> Result<()> {
         let mut latest_path = PathBuf::from(self.config.log_path());
         latest_path.push("latest");
@@ -187,16 +576,50 @@ impl ContinuousIntegration {
         unix::fs::symlink(&self.log_path, latest_path).map_err(Error::LogDirectory)
     }
 
-    fn report_header(&self) -> String {
+    fn report_header(&self, regressions: &Regressions) -> String {
         let success = self.scheduler.finished().filter(|r| r.success()).count();
 
-        format!(
+        let mut header = format!(
             "OVN CI - {} - {} - Success ({}) - Failure ({})",
             DateTime::from(SystemTime::now()).format("%d %B %Y"),
             Arch::get().name(),
             success,
             (self.scheduler.finished().count() - success)
-        )
+        );
+
+        if let Some(summary) = regressions.summary() {
+            header.push_str(" - ");
+            header.push_str(&summary);
+        }
+
+        header
+    }
+}
+
+/// Suite of the job with the names of its newly failing tests, none
+/// if those are not known.
+fn bisect_suite(runner: &Runner<Finished>, regressions: &Regressions) -> (Suite, Vec<String>) {
+    let tests = regressions
+        .new_failures(runner.name())
+        .map(str::to_string)
+        .collect();
+
+    (runner.suite().clone(), tests)
+}
+
+/// Result of the history query, the default if the history is not
+/// available.
+fn query_history<T: Default>(
+    history: Option<&History>,
+    query: impl FnOnce(&History) -> HistoryResult<T>,
+) -> T {
+    match history.map(query) {
+        Some(Ok(result)) => result,
+        Some(Err(e)) => {
+            eprintln!("Couldn't read run history: {e}");
+            T::default()
+        }
+        None => T::default(),
     }
 }
 
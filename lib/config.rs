@@ -1,11 +1,25 @@
+use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
 use std::io::Error as IoError;
-use std::path::Path;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_yaml::Error as YamlError;
 use thiserror::Error as ThisError;
 
+pub(crate) const DEFAULT_ARTIFACT: &str = "/root/logs.tgz";
+const DEFAULT_KEEP_FAILED_TTL: u64 = 24;
+const DEFAULT_HUGEPAGE_SIZE: u64 = 2048;
+const DEFAULT_HISTORY_FILE: &str = "history.db";
+const DEFAULT_BISECT_STEPS: usize = 8;
+const DEFAULT_FLAKY_RUNS: usize = 20;
+const DEFAULT_FLAKY_MIN_RATE: f64 = 0.1;
+const DEFAULT_KEEP_IMAGES: usize = 3;
+const DEFAULT_MIN_FREE_SPACE: u64 = 5;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(ThisError, Debug)]
@@ -14,6 +28,8 @@ pub enum Error {
     Read(#[source] IoError),
     #[error("Cannot read config file: {0}")]
     Parse(#[source] YamlError),
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -31,9 +47,24 @@ pub struct Configuration {
     timeout: Option<String>,
     #[serde(default)]
     cli_report_binary: Option<String>,
+    /// Number of failed VMs left running for debugging.
+    #[serde(default)]
+    keep_failed: Option<usize>,
+    /// How long the failed VMs are kept in hours.
+    #[serde(default)]
+    keep_failed_ttl: Option<u64>,
+    /// Path of the run history database, "history.db" in the log
+    /// path by default.
+    #[serde(default)]
+    history: Option<String>,
     git: Git,
     #[serde(default)]
     email: Option<Email>,
+    /// Bisect the newly failing jobs if set.
+    #[serde(default)]
+    bisect: Option<Bisect>,
+    #[serde(default)]
+    flaky: Flaky,
     vm: Vm,
     suites: Vec<Suite>,
 }
@@ -41,7 +72,63 @@ pub struct Configuration {
 impl Configuration {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = File::open(path).map_err(Error::Read)?;
-        let config: Configuration = serde_yaml::from_reader(file).map_err(Error::Parse)?;
+        let mut config: Configuration = serde_yaml::from_reader(file).map_err(Error::Parse)?;
+        config.vm.network.validate()?;
+        config.vm.rebuild.validate()?;
+
+        let images = config.vm.images();
+        for (index, image) in images.iter().enumerate() {
+            image.validate()?;
+
+            if images[..index]
+                .iter()
+                .any(|other| other.distro == image.distro)
+            {
+                return Err(Error::Invalid(format!(
+                    "distro \"{}\" has more than one image",
+                    image.distro.as_str()
+                )));
+            }
+        }
+
+        config.flaky.validate()?;
+
+        for profile in config.vm.profiles.values() {
+            profile.validate(config.jobs)?;
+        }
+
+        if let Some((suite, profile)) = config.suites.iter().find_map(|suite| {
+            suite
+                .profile
+                .as_ref()
+                .filter(|profile| !config.vm.profiles.contains_key(*profile))
+                .map(|profile| (&suite.name, profile))
+        }) {
+            return Err(Error::Invalid(format!(
+                "suite \"{suite}\" uses unknown profile \"{profile}\""
+            )));
+        }
+
+        for suite in &config.suites {
+            suite.validate(config.concurrent_limit())?;
+        }
+
+        config.suites = config
+            .suites
+            .iter()
+            .map(|suite| suite.expand(config.vm.distro, &images))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+
+        if config.concurrent_limit() + config.keep_failed() > config.vm.network.pool_size() {
+            return Err(Error::Invalid(format!(
+                "concurrent_limit {} with keep_failed {} is bigger than the DHCP pool ({} addresses)",
+                config.concurrent_limit(),
+                config.keep_failed(),
+                config.vm.network.pool_size()
+            )));
+        }
+
         Ok(config)
     }
 
@@ -61,14 +148,29 @@ impl Configuration {
         self.image_name.as_deref()
     }
 
-    pub fn concurrent_limit(&self) -> Option<usize> {
-        self.concurrent_limit
+    pub fn concurrent_limit(&self) -> usize {
+        self.concurrent_limit.unwrap_or(1)
     }
 
     pub fn cli_report_binary(&self) -> Option<&str> {
         self.cli_report_binary.as_deref()
     }
 
+    pub fn keep_failed(&self) -> usize {
+        self.keep_failed.unwrap_or(0)
+    }
+
+    pub fn keep_failed_ttl(&self) -> Duration {
+        Duration::from_secs(self.keep_failed_ttl.unwrap_or(DEFAULT_KEEP_FAILED_TTL) * 60 * 60)
+    }
+
+    pub fn history_path(&self) -> PathBuf {
+        self.history
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&self.log_path).join(DEFAULT_HISTORY_FILE))
+    }
+
     pub fn timeout(&self) -> &str {
         self.timeout.as_deref().unwrap_or("0")
     }
@@ -81,6 +183,14 @@ impl Configuration {
         self.email.as_ref()
     }
 
+    pub fn bisect(&self) -> Option<&Bisect> {
+        self.bisect.as_ref()
+    }
+
+    pub fn flaky(&self) -> &Flaky {
+        &self.flaky
+    }
+
     pub fn vm(&self) -> &Vm {
         &self.vm
     }
@@ -123,6 +233,10 @@ pub struct Email {
     reply_to: String,
     #[serde(default)]
     cc: Option<Vec<String>>,
+    /// Send the report only if a job or test status changed since
+    /// the previous run, for the passing runs too.
+    #[serde(default)]
+    only_on_change: bool,
 }
 
 impl Email {
@@ -141,6 +255,67 @@ impl Email {
     pub fn cc(&self) -> Option<&Vec<String>> {
         self.cc.as_ref()
     }
+
+    pub fn only_on_change(&self) -> bool {
+        self.only_on_change
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Bisect {
+    /// Maximum number of jobs run per bisected job.
+    #[serde(default)]
+    max_steps: Option<usize>,
+}
+
+impl Bisect {
+    pub fn max_steps(&self) -> usize {
+        self.max_steps.unwrap_or(DEFAULT_BISECT_STEPS)
+    }
+}
+
+/// Flaky test detection from the run history.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Flaky {
+    /// Number of previous runs the flake rate is computed from.
+    #[serde(default)]
+    runs: Option<usize>,
+    /// Failure rate from which a test that also passed is flaky.
+    #[serde(default)]
+    min_rate: Option<f64>,
+    /// What the jobs do with their known flaky tests, they run as
+    /// usual if not set.
+    #[serde(default)]
+    list: Option<FlakyList>,
+}
+
+impl Flaky {
+    pub fn runs(&self) -> usize {
+        self.runs.unwrap_or(DEFAULT_FLAKY_RUNS)
+    }
+
+    pub fn min_rate(&self) -> f64 {
+        self.min_rate.unwrap_or(DEFAULT_FLAKY_MIN_RATE)
+    }
+
+    pub fn list(&self) -> Option<FlakyList> {
+        self.list
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.min_rate()) {
+            return Err(Error::Invalid(format!(
+                "flaky min_rate {} isn't between 0 and 1",
+                self.min_rate()
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -148,7 +323,38 @@ impl Email {
 #[serde(deny_unknown_fields)]
 pub struct Vm {
     memory: u32,
+    #[serde(default)]
+    distro: Distro,
     release: String,
+    #[serde(default)]
+    network: Network,
+    #[serde(default)]
+    exec: ExecMode,
+    #[serde(default)]
+    ready: Ready,
+    #[serde(default)]
+    mirror: Option<Mirror>,
+    #[serde(default)]
+    source: ImageSource,
+    #[serde(default)]
+    cloud_image: Option<CloudImage>,
+    /// Images of additional distros the suites can run on.
+    #[serde(default)]
+    images: Vec<Image>,
+    /// Number of base image builds kept for rollback, only the newest
+    /// update of each build is kept.
+    #[serde(default)]
+    keep_images: Option<usize>,
+    /// Free space in GiB the smoke test requires on the guest root.
+    #[serde(default)]
+    min_free_space: Option<u64>,
+    #[serde(default)]
+    rebuild: Rebuild,
+    #[serde(default)]
+    customize: Customize,
+    /// Named VM profiles the suites can select, e.g. for DPDK.
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
 }
 
 impl Vm {
@@ -156,12 +362,745 @@ impl Vm {
         self.memory
     }
 
+    /// The default image described directly in the "vm" section
+    /// followed by the additional images.
+    pub fn images(&self) -> Vec<Image> {
+        let default = Image {
+            distro: self.distro,
+            release: self.release.clone(),
+            source: self.source,
+            cloud_image: self.cloud_image.clone(),
+            mirror: self.mirror.clone(),
+        };
+
+        std::iter::once(default)
+            .chain(self.images.iter().cloned())
+            .collect()
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn exec(&self) -> ExecMode {
+        self.exec
+    }
+
+    pub fn ready(&self) -> &Ready {
+        &self.ready
+    }
+
+    pub fn keep_images(&self) -> usize {
+        self.keep_images.unwrap_or(DEFAULT_KEEP_IMAGES).max(1)
+    }
+
+    pub fn min_free_space(&self) -> u64 {
+        self.min_free_space.unwrap_or(DEFAULT_MIN_FREE_SPACE)
+    }
+
+    pub fn rebuild(&self) -> &Rebuild {
+        &self.rebuild
+    }
+
+    pub fn customize(&self) -> &Customize {
+        &self.customize
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+}
+
+/// Resources of the VMs running the suites that select the profile.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct Profile {
+    /// Memory in MiB, "vm.memory" if not set.
+    memory: Option<u32>,
+    /// Back the guest memory by host hugepages.
+    hugepages: bool,
+    /// Hugepage size in KiB.
+    hugepage_size: Option<u64>,
+    /// Host CPUs the vCPUs are pinned to, e.g. "2-5,8". Jobs pinned to
+    /// the same CPUs run one at a time.
+    cpuset: Option<String>,
+    /// Host NUMA node the guest memory is allocated from.
+    numa_node: Option<u32>,
+    /// QEMU CPU model, host passthrough if not set.
+    cpu_model: Option<String>,
+    topology: Option<Topology>,
+    /// Additional disks attached read-only to the VM.
+    disks: Vec<ProfileDisk>,
+    /// Arguments passed directly to QEMU.
+    qemu_args: Vec<String>,
+}
+
+impl Profile {
+    pub fn memory(&self) -> Option<u32> {
+        self.memory
+    }
+
+    pub fn hugepages(&self) -> bool {
+        self.hugepages
+    }
+
+    pub fn hugepage_size(&self) -> u64 {
+        self.hugepage_size.unwrap_or(DEFAULT_HUGEPAGE_SIZE)
+    }
+
+    /// Host CPUs of the cpuset, empty if the vCPUs are not pinned.
+    pub fn cpus(&self) -> Vec<u32> {
+        self.cpuset
+            .as_deref()
+            .and_then(|cpuset| parse_cpuset(cpuset).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn numa_node(&self) -> Option<u32> {
+        self.numa_node
+    }
+
+    pub fn cpu_model(&self) -> Option<&str> {
+        self.cpu_model.as_deref()
+    }
+
+    pub fn topology(&self) -> Option<&Topology> {
+        self.topology.as_ref()
+    }
+
+    pub fn disks(&self) -> &[ProfileDisk] {
+        &self.disks
+    }
+
+    pub fn qemu_args(&self) -> &[String] {
+        &self.qemu_args
+    }
+
+    fn validate(&self, vcpu: usize) -> Result<()> {
+        if let Some(topology) = &self.topology
+            && topology.sockets * topology.cores * topology.threads != vcpu
+        {
+            return Err(Error::Invalid(format!(
+                "topology {}x{}x{} doesn't match {vcpu} jobs",
+                topology.sockets, topology.cores, topology.threads
+            )));
+        }
+
+        if let Some(cpuset) = self.cpuset.as_deref() {
+            parse_cpuset(cpuset)
+                .map_err(|e| Error::Invalid(format!("cpuset \"{cpuset}\": {e}")))?;
+        }
+
+        let size = self.hugepage_size();
+        if !size.is_power_of_two() || size < 4 {
+            return Err(Error::Invalid(format!(
+                "hugepage size {size} KiB is not a power of two"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Guest CPU topology, the product has to match the vCPU count.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    sockets: usize,
+    cores: usize,
+    threads: usize,
+}
+
+impl Topology {
+    pub fn sockets(&self) -> usize {
+        self.sockets
+    }
+
+    pub fn cores(&self) -> usize {
+        self.cores
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+}
+
+/// The flaky tests are passed to "ci.sh" as autotest keywords, the jobs
+/// with a test range run unchanged as autotest can't exclude tests from
+/// a range.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum FlakyList {
+    /// The jobs skip their flaky tests.
+    Skip,
+    /// The jobs skip their flaky tests, a recheck job per suite runs
+    /// them and reruns the failed ones.
+    Recheck,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct ProfileDisk {
+    path: String,
+    /// Image format, "qcow2" if not set.
+    #[serde(default)]
+    format: Option<String>,
+    /// Attach the disk read-only, e.g. when jobs run concurrently
+    /// with the same disk.
+    #[serde(default)]
+    readonly: bool,
+}
+
+impl ProfileDisk {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn format(&self) -> Option<&str> {
+        self.format.as_deref()
+    }
+
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+}
+
+/// Changes applied to the base images on every update.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct Customize {
+    /// Upgrade all installed packages.
+    upgrade: bool,
+    packages: Vec<String>,
+    /// Shell commands run in the image after the packages are installed.
+    commands: Vec<String>,
+    files: Vec<CustomizeFile>,
+    images: Vec<ContainerImage>,
+}
+
+impl Customize {
+    pub fn upgrade(&self) -> bool {
+        self.upgrade
+    }
+
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    pub fn files(&self) -> &[CustomizeFile] {
+        &self.files
+    }
+
+    pub fn images(&self) -> &[ContainerImage] {
+        &self.images
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct CustomizeFile {
+    /// Host file or directory.
+    source: String,
+    /// Guest directory the source is copied into.
+    destination: String,
+}
+
+impl CustomizeFile {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct ContainerImage {
+    name: String,
+    /// Additional local tag, e.g. "ovn-org/ovn-tests".
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+impl ContainerImage {
+    pub fn new(name: &str, tag: Option<&str>) -> Self {
+        ContainerImage {
+            name: name.to_string(),
+            tag: tag.map(str::to_string),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub enum Distro {
+    #[default]
+    Fedora,
+    CentosStream,
+    Ubuntu,
+}
+
+impl Distro {
+    const ALL: [Distro; 3] = [Distro::Fedora, Distro::CentosStream, Distro::Ubuntu];
+
+    pub fn from_name(name: &str) -> Option<Distro> {
+        Distro::ALL
+            .into_iter()
+            .find(|distro| distro.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Distro::Fedora => "fedora",
+            Distro::CentosStream => "centos-stream",
+            Distro::Ubuntu => "ubuntu",
+        }
+    }
+
+    pub fn as_name(&self) -> &'static str {
+        match self {
+            Distro::Fedora => "Fedora",
+            Distro::CentosStream => "CentOS Stream",
+            Distro::Ubuntu => "Ubuntu",
+        }
+    }
+}
+
+/// Base image of single distro.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Image {
+    distro: Distro,
+    release: String,
+    #[serde(default)]
+    source: ImageSource,
+    #[serde(default)]
+    cloud_image: Option<CloudImage>,
+    #[serde(default)]
+    mirror: Option<Mirror>,
+}
+
+impl Image {
+    pub fn distro(&self) -> Distro {
+        self.distro
+    }
+
     pub fn release(&self) -> &str {
         &self.release
     }
+
+    pub fn mirror(&self) -> Option<&Mirror> {
+        self.mirror.as_ref()
+    }
+
+    pub fn source(&self) -> ImageSource {
+        self.source
+    }
+
+    pub fn cloud_image(&self) -> Option<&CloudImage> {
+        self.cloud_image.as_ref()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(mirror) = self.mirror() {
+            mirror.validate()?;
+        }
+
+        // There is no kickstart equivalent for Ubuntu.
+        if self.distro == Distro::Ubuntu && self.source == ImageSource::Installer {
+            return Err(Error::Invalid(format!(
+                "distro \"{}\" supports only the \"cloud_image\" source",
+                self.distro.as_str()
+            )));
+        }
+
+        // The customized cloud image reaches only remote dnf repositories.
+        if self.source == ImageSource::CloudImage
+            && let Some(mirror) = self.mirror()
+            && (self.distro == Distro::Ubuntu || !mirror.is_remote())
+        {
+            return Err(Error::Invalid(format!(
+                "mirror of the \"cloud_image\" source of \"{}\" has to be dnf repository URLs",
+                self.distro.as_str()
+            )));
+        }
+
+        if self.source == ImageSource::CloudImage && self.cloud_image.is_none() {
+            return Err(Error::Invalid(format!(
+                "source \"cloud_image\" of \"{}\" requires the \"cloud_image\" section",
+                self.distro.as_str()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum ImageSource {
+    /// Full Anaconda installation with the kickstart.
+    #[default]
+    Installer,
+    /// Customized Fedora or CentOS Stream cloud image.
+    CloudImage,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct CloudImage {
+    /// URL or local path of the qcow2 cloud image.
+    location: String,
+    /// Expected SHA-256 of the image.
+    checksum: String,
+    /// Partition grown to fill the resized disk, e.g. "/dev/sda5".
+    #[serde(default)]
+    expand: Option<String>,
+}
+
+impl CloudImage {
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn is_local(&self) -> bool {
+        !is_url(&self.location)
+    }
+
+    pub fn checksum(&self) -> &str {
+        &self.checksum
+    }
+
+    pub fn expand(&self) -> Option<&str> {
+        self.expand.as_deref()
+    }
+}
+
+/// Package sources for the base image installation, used instead
+/// of the public Fedora mirrors.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    /// Installation tree URL, local tree directory or path to a local
+    /// installation ISO.
+    #[serde(default)]
+    url: Option<String>,
+    /// Additional repositories reachable from the installer.
+    #[serde(default)]
+    repos: Vec<Repo>,
+}
+
+impl Mirror {
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn repos(&self) -> &[Repo] {
+        &self.repos
+    }
+
+    /// The mirror is a local ISO attached as CD-ROM.
+    pub fn is_local(&self) -> bool {
+        self.url().is_some_and(|url| Path::new(url).is_file())
+    }
+
+    /// Tree and repos are all network URLs.
+    pub fn is_remote(&self) -> bool {
+        self.url().is_none_or(is_url) && self.repos.iter().all(|repo| is_url(&repo.url))
+    }
+
+    /// Installation tree for virt-install, local trees as plain paths.
+    pub fn location(&self) -> Option<&str> {
+        self.url()
+            .map(|url| url.strip_prefix("file://").unwrap_or(url))
+    }
+
+    /// Installation tree for the kickstart.
+    pub fn baseurl(&self) -> Option<String> {
+        self.url().map(baseurl)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(url) = self.url()
+            && !is_url(url)
+            && !is_tree(url)
+            && !Path::new(url).is_file()
+        {
+            return Err(Error::Invalid(format!(
+                "mirror \"{url}\" is neither URL, existing directory nor existing ISO"
+            )));
+        }
+
+        if let Some(repo) = self
+            .repos
+            .iter()
+            .find(|repo| !is_url(&repo.url) && !is_tree(&repo.url))
+        {
+            return Err(Error::Invalid(format!(
+                "repo \"{}\" has to be an URL or existing directory, got \"{}\"",
+                repo.name, repo.url
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Repo {
+    name: String,
+    url: String,
+}
+
+impl Repo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Repository URL for the kickstart.
+    pub fn baseurl(&self) -> String {
+        baseurl(&self.url)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct Ready {
+    /// Deadline for the whole readiness check in seconds.
+    timeout: u64,
+    /// Initial delay between probes in milliseconds, doubled after every miss.
+    backoff: u64,
+    /// Upper bound for the delay between probes in milliseconds.
+    max_backoff: u64,
+    /// Wait for "systemctl is-system-running" to report "running" or
+    /// "degraded", the failed units of the latter are saved in the log.
+    system_running: bool,
+}
+
+impl Ready {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    pub fn backoff(&self) -> Duration {
+        Duration::from_millis(self.backoff)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff)
+    }
+
+    pub fn system_running(&self) -> bool {
+        self.system_running
+    }
+}
+
+/// When the base images are rebuilt, the first matching rule wins.
+/// Without the section the images are rebuilt on the first day of the
+/// month, a present section only uses the rules it lists.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Rebuild {
+    /// Days of month in cron syntax, e.g. "1", "1,15", "1-7" or "*/7".
+    #[serde(default)]
+    days: Option<String>,
+    /// Maximal age of the image in days.
+    #[serde(default)]
+    max_age: Option<u64>,
+    /// Rebuild when the configured release differs from the image.
+    #[serde(default = "default_on_release_change")]
+    on_release_change: bool,
+    /// Rebuild after this many consecutive smoke test failures.
+    #[serde(default)]
+    smoke_failures: Option<u32>,
+}
+
+fn default_on_release_change() -> bool {
+    true
+}
+
+impl Rebuild {
+    pub fn is_scheduled(&self, day: u32) -> bool {
+        self.days
+            .as_deref()
+            .and_then(|days| parse_days(days).ok())
+            .is_some_and(|days| days.contains(&day))
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    pub fn on_release_change(&self) -> bool {
+        self.on_release_change
+    }
+
+    pub fn smoke_failures(&self) -> Option<u32> {
+        self.smoke_failures
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(days) = self.days.as_deref() {
+            parse_days(days)
+                .map_err(|e| Error::Invalid(format!("rebuild days \"{days}\": {e}")))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Rebuild {
+    fn default() -> Self {
+        Rebuild {
+            days: Some("1".to_string()),
+            max_age: None,
+            on_release_change: default_on_release_change(),
+            smoke_failures: None,
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+impl Default for Ready {
+    fn default() -> Self {
+        Ready {
+            timeout: 600,
+            backoff: 500,
+            max_backoff: 10_000,
+            system_running: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum ExecMode {
+    /// Run commands over ssh with the injected root key.
+    #[default]
+    Ssh,
+    /// Run commands through the qemu-guest-agent channel.
+    Agent,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    address: Ipv4Addr,
+    prefix: u8,
+    dhcp_start: Ipv4Addr,
+    dhcp_end: Ipv4Addr,
+}
+
+impl Network {
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0))
+    }
+
+    pub fn dhcp_start(&self) -> Ipv4Addr {
+        self.dhcp_start
+    }
+
+    pub fn dhcp_end(&self) -> Ipv4Addr {
+        self.dhcp_end
+    }
+
+    pub fn pool_size(&self) -> usize {
+        (u32::from(self.dhcp_end).saturating_sub(u32::from(self.dhcp_start)) as usize) + 1
+    }
+
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask());
+        u32::from(ip) & mask == u32::from(self.address) & mask
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.prefix > 30 {
+            return Err(Error::Invalid(format!(
+                "network prefix /{} is too small for a DHCP pool",
+                self.prefix
+            )));
+        }
+
+        if !self.contains(self.dhcp_start) || !self.contains(self.dhcp_end) {
+            return Err(Error::Invalid(format!(
+                "DHCP range {}-{} is outside of {}/{}",
+                self.dhcp_start, self.dhcp_end, self.address, self.prefix
+            )));
+        }
+
+        if self.dhcp_start > self.dhcp_end {
+            return Err(Error::Invalid(format!(
+                "DHCP range start {} is after end {}",
+                self.dhcp_start, self.dhcp_end
+            )));
+        }
+
+        let address = self.address;
+        if (self.dhcp_start..=self.dhcp_end).contains(&address) {
+            return Err(Error::Invalid(format!(
+                "network address {address} is inside of the DHCP range"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Network {
+            address: Ipv4Addr::new(192, 168, 100, 1),
+            prefix: 24,
+            dhcp_start: Ipv4Addr::new(192, 168, 100, 10),
+            dhcp_end: Ipv4Addr::new(192, 168, 100, 250),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(deny_unknown_fields)]
 enum Compiler {
@@ -185,7 +1124,7 @@ impl Compiler {
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 enum SuiteType {
@@ -194,6 +1133,8 @@ enum SuiteType {
     SystemUserspace,
     SystemDpdk,
     Dist,
+    /// Tests spanning several VMs, requires "topology".
+    Multinode,
 }
 
 impl SuiteType {
@@ -204,6 +1145,7 @@ impl SuiteType {
             SuiteType::SystemUserspace => "system-test-userspace",
             SuiteType::SystemDpdk => "system-test-dpdk",
             SuiteType::Dist => "dist-test",
+            SuiteType::Multinode => "multinode-test",
         }
     }
 
@@ -214,20 +1156,35 @@ impl SuiteType {
             SuiteType::SystemUserspace => "system-userspace",
             SuiteType::SystemDpdk => "system-dpdk",
             SuiteType::Dist => "dist",
+            SuiteType::Multinode => "multinode",
         }
     }
 
     fn extra_env(&self) -> Option<(&str, &str)> {
         match self {
-            SuiteType::Unit | SuiteType::System | SuiteType::SystemUserspace | SuiteType::Dist => {
-                None
-            }
+            SuiteType::Unit
+            | SuiteType::System
+            | SuiteType::SystemUserspace
+            | SuiteType::Dist
+            | SuiteType::Multinode => None,
             SuiteType::SystemDpdk => Some(("DPDK", "dpdk")),
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// VMs of a multinode suite, the first node runs "ci.sh".
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+struct NodeTopology {
+    nodes: usize,
+    /// Isolated networks connecting all the nodes, in addition
+    /// to the management network.
+    #[serde(default)]
+    networks: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(deny_unknown_fields)]
 pub struct Suite {
@@ -248,6 +1205,18 @@ pub struct Suite {
     unstable: bool,
     #[serde(default)]
     recheck: bool,
+    #[serde(default)]
+    artifacts: Option<Vec<String>>,
+    /// Distros to run the suite on, the default "vm.distro" if not set.
+    #[serde(default)]
+    distros: Option<Vec<Distro>>,
+    /// Name of the VM profile from "vm.profiles".
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    topology: Option<NodeTopology>,
+    #[serde(skip_deserializing)]
+    distro: Distro,
 }
 
 impl Suite {
@@ -292,7 +1261,12 @@ impl Suite {
     }
 
     pub fn name(&self) -> String {
-        let mut name = format!("{} {}", self.name, self.compiler.as_name());
+        let mut name = format!(
+            "{} {} [{}]",
+            self.name,
+            self.compiler.as_name(),
+            self.distro.as_name()
+        );
 
         if let Some(ty) = self.suite_type {
             name.push_str(" - ");
@@ -332,6 +1306,125 @@ impl Suite {
         name
     }
 
+    /// Guest paths or globs collected after the job, "/root/logs.tgz"
+    /// created by "ci.sh --archive-logs" unless configured otherwise.
+    pub fn artifacts(&self) -> Vec<String> {
+        self.artifacts
+            .clone()
+            .unwrap_or_else(|| vec![DEFAULT_ARTIFACT.to_string()])
+    }
+
+    pub fn distro(&self) -> Distro {
+        self.distro
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn has_test_range(&self) -> bool {
+        self.test_range.is_some()
+    }
+
+    pub fn is_recheck(&self) -> bool {
+        self.recheck
+    }
+
+    /// Copy of the suite rerunning its failed tests.
+    pub fn with_recheck(&self) -> Suite {
+        Suite {
+            recheck: true,
+            ..self.clone()
+        }
+    }
+
+    /// Copy of the suite running only the given tests, e.g. "3 17 42"
+    /// or "-k 'lex'".
+    pub fn with_test_range(&self, range: String) -> Suite {
+        Suite {
+            test_range: Some(range),
+            ..self.clone()
+        }
+    }
+
+    /// Number of VMs the suite runs on.
+    pub fn nodes(&self) -> usize {
+        self.topology
+            .as_ref()
+            .map(|topology| topology.nodes)
+            .unwrap_or(1)
+    }
+
+    pub fn networks(&self) -> &[String] {
+        self.topology
+            .as_ref()
+            .map(|topology| topology.networks.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn validate(&self, concurrent_limit: usize) -> Result<()> {
+        let invalid =
+            |reason: String| Err(Error::Invalid(format!("suite \"{}\" {reason}", self.name)));
+        let multinode = self.suite_type == Some(SuiteType::Multinode);
+
+        let Some(topology) = &self.topology else {
+            if multinode {
+                return invalid("of type \"multinode\" requires \"topology\"".to_string());
+            }
+            return Ok(());
+        };
+
+        if !multinode {
+            return invalid("has \"topology\" without type \"multinode\"".to_string());
+        }
+
+        if topology.nodes < 2 || topology.nodes > concurrent_limit {
+            return invalid(format!(
+                "needs 2 to {concurrent_limit} (concurrent_limit) nodes, got {}",
+                topology.nodes
+            ));
+        }
+
+        for (index, network) in topology.networks.iter().enumerate() {
+            if network.is_empty()
+                || !network
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                return invalid(format!("has invalid network name \"{network}\""));
+            }
+
+            if topology.networks[..index].contains(network) {
+                return invalid(format!("has duplicate network \"{network}\""));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates copy of the suite for every distro it should run on.
+    fn expand(&self, default: Distro, images: &[Image]) -> Result<Vec<Suite>> {
+        let distros = self.distros.clone().unwrap_or_else(|| vec![default]);
+
+        distros
+            .into_iter()
+            .map(|distro| {
+                if !images.iter().any(|image| image.distro == distro) {
+                    return Err(Error::Invalid(format!(
+                        "suite \"{}\" runs on \"{}\" that doesn't have an image",
+                        self.name,
+                        distro.as_str()
+                    )));
+                }
+
+                Ok(Suite {
+                    distro,
+                    ..self.clone()
+                })
+            })
+            .collect()
+    }
+
     pub fn is_cpu_intensive(&self) -> bool {
         matches!(
             self.suite_type,
@@ -339,3 +1432,83 @@ impl Suite {
         )
     }
 }
+
+fn is_url(url: &str) -> bool {
+    ["http://", "https://", "ftp://", "nfs:"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+}
+
+/// Local installation trees and repositories are either directories
+/// or file:// URLs.
+fn is_tree(url: &str) -> bool {
+    url.starts_with("file://") || Path::new(url).is_dir()
+}
+
+/// Turns local directories into absolute file:// URLs.
+fn baseurl(url: &str) -> String {
+    if is_url(url) || url.starts_with("file://") {
+        return url.to_string();
+    }
+
+    let path = fs::canonicalize(url).unwrap_or_else(|_| PathBuf::from(url));
+    format!("file://{}", path.display())
+}
+
+/// Parses the cpuset list, e.g. "2-5,8", into the list of CPUs.
+fn parse_cpuset(cpuset: &str) -> std::result::Result<Vec<u32>, String> {
+    let parse = |cpu: &str| {
+        cpu.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("invalid CPU \"{cpu}\""))
+    };
+
+    let mut cpus = Vec::new();
+    for item in cpuset.split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(format!("invalid range \"{item}\""));
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(parse(item)?),
+        }
+    }
+
+    Ok(cpus)
+}
+
+/// Parses the cron day of month field into the list of days.
+fn parse_days(spec: &str) -> std::result::Result<Vec<u32>, String> {
+    let parse = |day: &str| match day.parse::<u32>() {
+        Ok(day) if (1..=31).contains(&day) => Ok(day),
+        _ => Err(format!("invalid day \"{day}\"")),
+    };
+
+    let mut days = Vec::new();
+    for item in spec.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step \"{step}\"")),
+            },
+            None => (item, 1),
+        };
+
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (1, 31),
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None if step > 1 => (parse(range)?, 31),
+            None => (parse(range)?, parse(range)?),
+        };
+        if start > end {
+            return Err(format!("invalid range \"{range}\""));
+        }
+
+        days.extend((start..=end).step_by(step));
+    }
+
+    Ok(days)
+}
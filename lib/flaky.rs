@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use crate::regression::RunStatus;
+
+/// Failure rates of the tests that both passed and failed within the
+/// previous runs, keyed by the job and test name.
+#[derive(Debug, Default)]
+pub struct FlakeRates {
+    rates: HashMap<(String, String), f64>,
+}
+
+impl FlakeRates {
+    pub fn new(history: &[RunStatus], min_rate: f64) -> Self {
+        let mut counts = HashMap::<(&str, &str), (usize, usize)>::new();
+
+        for (job, test, failed) in history.iter().flat_map(RunStatus::tests) {
+            let (failures, runs) = counts.entry((job, test)).or_default();
+            *failures += usize::from(failed);
+            *runs += 1;
+        }
+
+        let rates = counts
+            .into_iter()
+            .filter(|(_, (failures, runs))| *failures > 0 && failures < runs)
+            .map(|(key, (failures, runs))| (key, failures as f64 / runs as f64))
+            .filter(|(_, rate)| *rate >= min_rate)
+            .map(|((job, test), rate)| ((job.to_string(), test.to_string()), rate))
+            .collect();
+
+        FlakeRates { rates }
+    }
+
+    pub fn rate(&self, job: &str, test: &str) -> Option<f64> {
+        self.rates
+            .get(&(job.to_string(), test.to_string()))
+            .copied()
+    }
+
+    /// Names of the flaky tests of the job, sorted.
+    pub fn tests(&self, job: &str) -> Vec<String> {
+        let mut tests = self
+            .rates
+            .keys()
+            .filter(|(name, _)| name == job)
+            .map(|(_, test)| test.clone())
+            .collect::<Vec<_>>();
+        tests.sort();
+        tests
+    }
+}
@@ -19,6 +19,16 @@ pub enum Error {
     Update(String, String),
     #[error("Cannot determine commit hash \"{0}\": {1}")]
     CommitHash(String, String),
+    #[error("Cannot list commits of \"{0}\": {1}")]
+    RevList(String, String),
+    #[error("Cannot get subject of commit \"{0}\": {1}")]
+    Subject(String, String),
+    #[error("Cannot checkout \"{0}\": {1}")]
+    Checkout(String, String),
+    #[error("Cannot add worktree \"{0}\": {1}")]
+    WorktreeAdd(String, String),
+    #[error("Cannot remove worktree \"{0}\": {1}")]
+    WorktreeRemove(String, String),
 }
 
 pub struct Git<'a> {
@@ -55,6 +65,77 @@ impl<'a> Git<'a> {
         Ok(stdout)
     }
 
+    /// Commits after `good` up to `bad` following the first parents,
+    /// the oldest first.
+    pub fn rev_list(&self, good: &str, bad: &str) -> Result<Vec<String>> {
+        let stdout = Command::new("git")
+            .arg("rev-list")
+            .arg("--first-parent")
+            .arg("--reverse")
+            .arg(format!("{good}..{bad}"))
+            .current_dir(self.path)
+            .output()?
+            .stdout()
+            .map_err(|e| Error::RevList(self.path.to_string(), e))?;
+
+        Ok(stdout.lines().map(str::to_string).collect())
+    }
+
+    pub fn subject(&self, commit: &str) -> Result<String> {
+        let stdout = Command::new("git")
+            .arg("log")
+            .arg("-1")
+            .arg("--format=%s")
+            .arg(commit)
+            .current_dir(self.path)
+            .output()?
+            .stdout()
+            .map_err(|e| Error::Subject(commit.to_string(), e))?
+            .trim_end()
+            .to_string();
+
+        Ok(stdout)
+    }
+
+    pub fn checkout(&self, commit: &str) -> Result<()> {
+        Command::new("git")
+            .arg("checkout")
+            .arg("--detach")
+            .arg("--force")
+            .arg(commit)
+            .current_dir(self.path)
+            .output()?
+            .status_ok()
+            .map_err(|e| Error::Checkout(commit.to_string(), e))
+    }
+
+    /// Adds detached worktree at `path`, the main checkout stays as is.
+    pub fn worktree_add(&self, path: &str, commit: &str) -> Result<()> {
+        Command::new("git")
+            .arg("worktree")
+            .arg("add")
+            .arg("--detach")
+            .arg("--force")
+            .arg(path)
+            .arg(commit)
+            .current_dir(self.path)
+            .output()?
+            .status_ok()
+            .map_err(|e| Error::WorktreeAdd(path.to_string(), e))
+    }
+
+    pub fn worktree_remove(&self, path: &str) -> Result<()> {
+        Command::new("git")
+            .arg("worktree")
+            .arg("remove")
+            .arg("--force")
+            .arg(path)
+            .current_dir(self.path)
+            .output()?
+            .status_ok()
+            .map_err(|e| Error::WorktreeRemove(path.to_string(), e))
+    }
+
     fn submodule_parent(&self) -> Result<String> {
         let stdout = Command::new("git")
             .arg("rev-parse")
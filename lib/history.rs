@@ -0,0 +1,628 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::DirBuilder;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use rusqlite::{Connection, Error as SqlError, OptionalExtension, Row, params, params_from_iter};
+use thiserror::Error as ThisError;
+
+use crate::regression::RunStatus;
+use crate::results::{Run, timestamp};
+use crate::runner::{Finished, Runner};
+use crate::testsuite::Status;
+
+/// Version of the database schema, bumped on every incompatible change.
+const SCHEMA_VERSION: u32 = 1;
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    start TEXT NOT NULL,
+    end TEXT NOT NULL,
+    arch TEXT NOT NULL,
+    ovn_hash TEXT NOT NULL,
+    ovs_hash TEXT NOT NULL,
+    log_path TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    name TEXT NOT NULL,
+    status TEXT NOT NULL,
+    error_kind TEXT,
+    error TEXT,
+    start TEXT NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    log_path TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tests (
+    id INTEGER PRIMARY KEY,
+    job_id INTEGER NOT NULL REFERENCES jobs(id),
+    suite TEXT NOT NULL,
+    number INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    location TEXT NOT NULL,
+    status TEXT NOT NULL,
+    message TEXT NOT NULL,
+    duration_ms INTEGER
+);
+CREATE INDEX IF NOT EXISTS jobs_run ON jobs(run_id);
+CREATE INDEX IF NOT EXISTS tests_job ON tests(job_id);
+CREATE INDEX IF NOT EXISTS tests_name ON tests(name);
+";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("Cannot create history directory: {0}")]
+    Directory(#[source] IoError),
+    #[error("History database error: {0}")]
+    Sql(#[from] SqlError),
+    #[error("History database has schema version {0}, expected {SCHEMA_VERSION}")]
+    Version(u32),
+    #[error("Invalid date \"{0}\", expected YYYY-MM-DD")]
+    Date(String),
+}
+
+/// Results of the previous runs kept in a SQLite database.
+pub struct History {
+    connection: Connection,
+}
+
+impl History {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .create(parent)
+                .map_err(Error::Directory)?;
+        }
+
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Creates the schema in a new database, an existing one must
+    /// have the current schema version.
+    fn from_connection(connection: Connection) -> Result<Self> {
+        let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        match version {
+            0 => {
+                connection.execute_batch(SCHEMA)?;
+                connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            }
+            SCHEMA_VERSION => {}
+            version => return Err(Error::Version(version)),
+        }
+
+        Ok(History { connection })
+    }
+
+    /// Records the run with all its jobs and their parsed tests,
+    /// returns the id of the run.
+    pub fn record<'a, I: Iterator<Item = &'a Runner<Finished>>>(
+        &mut self,
+        run: &Run,
+        log_path: &Path,
+        runners: I,
+    ) -> Result<i64> {
+        let transaction = self.connection.transaction()?;
+
+        transaction.execute(
+            "INSERT INTO runs (start, end, arch, ovn_hash, ovs_hash, log_path)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                run.start(),
+                run.end(),
+                run.arch(),
+                run.ovn_hash(),
+                run.ovs_hash(),
+                log_path.to_string_lossy()
+            ],
+        )?;
+        let run_id = transaction.last_insert_rowid();
+
+        for runner in runners {
+            transaction.execute(
+                "INSERT INTO jobs (run_id, name, status, error_kind, error, start, duration_ms, log_path)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    run_id,
+                    runner.name(),
+                    if runner.success() {
+                        "success"
+                    } else {
+                        "failure"
+                    },
+                    runner.error().map(|error| error.kind()),
+                    runner.error().map(|error| error.to_string()),
+                    timestamp(runner.started()),
+                    millis(runner.duration()),
+                    runner.log_path().to_string_lossy()
+                ],
+            )?;
+            let job_id = transaction.last_insert_rowid();
+
+            for suite in runner.tests() {
+                for case in suite.cases() {
+                    transaction.execute(
+                        "INSERT INTO tests (job_id, suite, number, name, location, status, message, duration_ms)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        params![
+                            job_id,
+                            suite.name(),
+                            case.number(),
+                            case.name(),
+                            case.location(),
+                            case.status().as_str(),
+                            case.message(),
+                            case.duration().map(millis)
+                        ],
+                    )?;
+                }
+            }
+        }
+
+        transaction.commit()?;
+        Ok(run_id)
+    }
+
+    /// Statuses of the last `runs` runs on the arch, the most recent
+    /// first.
+    pub fn recent(&self, arch: &str, runs: usize) -> Result<Vec<RunStatus>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id FROM runs WHERE arch = ?1 ORDER BY id DESC LIMIT ?2")?;
+        let ids = statement
+            .query_map(params![arch, runs], |row| row.get::<_, i64>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        ids.into_iter().map(|id| self.run_status(id)).collect()
+    }
+
+    /// OVN commit of the last run on the arch.
+    pub fn last_ovn_hash(&self, arch: &str) -> Result<Option<String>> {
+        let hash = self
+            .connection
+            .query_row(
+                "SELECT ovn_hash FROM runs WHERE arch = ?1 ORDER BY id DESC LIMIT 1",
+                [arch],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(hash)
+    }
+
+    fn run_status(&self, run_id: i64) -> Result<RunStatus> {
+        let mut status = RunStatus::default();
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT name, status FROM jobs WHERE run_id = ?1")?;
+        let mut rows = statement.query([run_id])?;
+        while let Some(row) = rows.next()? {
+            status.job(
+                &row.get::<_, String>(0)?,
+                row.get::<_, String>(1)? != "success",
+            );
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT jobs.name, tests.name, tests.status
+             FROM tests JOIN jobs ON tests.job_id = jobs.id
+             WHERE jobs.run_id = ?1",
+        )?;
+        let mut rows = statement.query([run_id])?;
+        while let Some(row) = rows.next()? {
+            if let Some(test_status) = Status::from_name(&row.get::<_, String>(2)?) {
+                status.test(
+                    &row.get::<_, String>(0)?,
+                    &row.get::<_, String>(1)?,
+                    test_status,
+                );
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Jobs matching the filter, oldest first.
+    pub fn jobs(&self, filter: &Filter) -> Result<Vec<JobEntry>> {
+        let (conditions, values) = filter.conditions();
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT runs.start, runs.arch, runs.ovn_hash, jobs.name, jobs.status, jobs.duration_ms, jobs.error
+             FROM jobs JOIN runs ON jobs.run_id = runs.id
+             {conditions}
+             ORDER BY runs.id, jobs.id"
+        ))?;
+
+        let entries = statement
+            .query_map(params_from_iter(values), |row| {
+                Ok(JobEntry {
+                    run: RunEntry::from_row(row)?,
+                    name: row.get(3)?,
+                    status: row.get(4)?,
+                    duration: Duration::from_millis(row.get(5)?),
+                    error: row.get(6)?,
+                })
+            })?
+            .collect::<std::result::Result<_, _>>()?;
+
+        Ok(entries)
+    }
+
+    /// Tests matching the filter, oldest first.
+    pub fn tests(&self, filter: &Filter) -> Result<Vec<TestEntry>> {
+        let (conditions, values) = filter.conditions();
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT runs.start, runs.arch, runs.ovn_hash, jobs.name, tests.number, tests.name, tests.status, tests.message
+             FROM tests JOIN jobs ON tests.job_id = jobs.id JOIN runs ON jobs.run_id = runs.id
+             {conditions}
+             ORDER BY runs.id, jobs.id, tests.number"
+        ))?;
+
+        let entries = statement
+            .query_map(params_from_iter(values), |row| {
+                Ok(TestEntry {
+                    run: RunEntry::from_row(row)?,
+                    job: row.get(3)?,
+                    number: row.get(4)?,
+                    name: row.get(5)?,
+                    status: row.get(6)?,
+                    message: row.get(7)?,
+                })
+            })?
+            .collect::<std::result::Result<_, _>>()?;
+
+        Ok(entries)
+    }
+}
+
+/// Query filter, the suite and test match as substrings of the job
+/// and test names, the dates are inclusive.
+#[derive(Debug, Default)]
+pub struct Filter {
+    suite: Option<String>,
+    test: Option<String>,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+}
+
+impl Filter {
+    pub fn suite(mut self, suite: &str) -> Self {
+        self.suite = Some(suite.to_string());
+        self
+    }
+
+    pub fn test(mut self, test: &str) -> Self {
+        self.test = Some(test.to_string());
+        self
+    }
+
+    pub fn since(mut self, date: &str) -> Result<Self> {
+        self.since = Some(parse_date(date)?);
+        Ok(self)
+    }
+
+    pub fn until(mut self, date: &str) -> Result<Self> {
+        self.until = Some(parse_date(date)?);
+        Ok(self)
+    }
+
+    pub fn has_test(&self) -> bool {
+        self.test.is_some()
+    }
+
+    fn conditions(&self) -> (String, Vec<String>) {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+
+        if let Some(suite) = &self.suite {
+            conditions.push("jobs.name LIKE '%' || ? || '%'");
+            values.push(suite.clone());
+        }
+
+        if let Some(test) = &self.test {
+            conditions.push("tests.name LIKE '%' || ? || '%'");
+            values.push(test.clone());
+        }
+
+        if let Some(since) = &self.since {
+            conditions.push("date(runs.start) >= ?");
+            values.push(since.format(DATE_FORMAT).to_string());
+        }
+
+        if let Some(until) = &self.until {
+            conditions.push("date(runs.start) <= ?");
+            values.push(until.format(DATE_FORMAT).to_string());
+        }
+
+        if conditions.is_empty() {
+            (String::new(), values)
+        } else {
+            (format!("WHERE {}", conditions.join(" AND ")), values)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RunEntry {
+    start: String,
+    arch: String,
+    ovn_hash: String,
+}
+
+impl RunEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(RunEntry {
+            start: row.get(0)?,
+            arch: row.get(1)?,
+            ovn_hash: row.get(2)?,
+        })
+    }
+}
+
+impl Display for RunEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{} {} {}",
+            self.start.get(..19).unwrap_or(&self.start),
+            self.arch,
+            self.ovn_hash.get(..12).unwrap_or(&self.ovn_hash)
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct JobEntry {
+    run: RunEntry,
+    name: String,
+    status: String,
+    duration: Duration,
+    error: Option<String>,
+}
+
+impl Display for JobEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let secs = self.duration.as_secs();
+        write!(
+            f,
+            "{} {:<7} {:02}m {:02}s {}",
+            self.run,
+            self.status,
+            secs / 60,
+            secs % 60,
+            self.name
+        )?;
+
+        match &self.error {
+            Some(error) => write!(f, ": {error}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TestEntry {
+    run: RunEntry,
+    job: String,
+    number: usize,
+    name: String,
+    status: String,
+    message: String,
+}
+
+impl Display for TestEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{} {:<16} {}. {} ({}): {}",
+            self.run, self.status, self.number, self.name, self.job, self.message
+        )
+    }
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, DATE_FORMAT).map_err(|_| Error::Date(date.to_string()))
+}
+
+fn millis(duration: Duration) -> i64 {
+    i64::try_from(duration.as_millis()).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::config::Suite;
+    use crate::runner::Error as RunnerError;
+    use crate::testsuite::TestSuite;
+
+    const LOG: &str = include_str!("testdata/testsuite.log");
+    const GCC: &str = "ovn GCC [Fedora]";
+    const CLANG: &str = "ovn Clang [Fedora]";
+
+    fn history() -> History {
+        History::from_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    /// Runner of the "ovn" suite, named e.g. "ovn GCC [Fedora]".
+    fn runner(compiler: &str, error: Option<RunnerError>, log: Option<&str>) -> Runner<Finished> {
+        let suite: Suite =
+            serde_yaml::from_str(&format!("name: ovn\ncompiler: {compiler}")).unwrap();
+        let tests = log
+            .map(|log| vec![TestSuite::parse("testsuite", log)])
+            .unwrap_or_default();
+
+        Runner::finished(suite, error, tests)
+    }
+
+    fn record(history: &mut History, ovn_hash: &str, runners: &[Runner<Finished>]) -> i64 {
+        let run = Run::new(ovn_hash, "ovs", "x86_64", SystemTime::now(), &[]);
+        history
+            .record(&run, Path::new("/logs/run"), runners.iter())
+            .unwrap()
+    }
+
+    fn names<T>(entries: &[T], name: impl Fn(&T) -> &str) -> Vec<&str> {
+        entries.iter().map(name).collect()
+    }
+
+    #[test]
+    fn schema_version() {
+        let history = history();
+        let version: u32 = history
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection
+            .pragma_update(None, "user_version", SCHEMA_VERSION)
+            .unwrap();
+        assert!(History::from_connection(connection).is_ok());
+
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(matches!(
+            History::from_connection(connection),
+            Err(Error::Version(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn record_runs() {
+        let mut history = history();
+        assert_eq!(history.last_ovn_hash("x86_64").unwrap(), None);
+
+        let first = record(&mut history, "aaa", &[runner("gcc", None, Some(LOG))]);
+        let second = record(
+            &mut history,
+            "bbb",
+            &[
+                runner("gcc", None, None),
+                runner("clang", Some(RunnerError::NoSlot), None),
+            ],
+        );
+        assert!(second > first);
+
+        assert_eq!(
+            history.last_ovn_hash("x86_64").unwrap().as_deref(),
+            Some("bbb")
+        );
+        assert_eq!(history.last_ovn_hash("aarch64").unwrap(), None);
+
+        let recent = history.recent("x86_64", 10).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].job_failed(CLANG), Some(true));
+        assert_eq!(recent[0].job_failed(GCC), Some(false));
+        assert_eq!(recent[0].tests().count(), 0);
+        assert_eq!(recent[1].job_failed(CLANG), None);
+
+        let mut tests = recent[1].tests().collect::<Vec<_>>();
+        tests.sort_unstable();
+        assert_eq!(
+            tests,
+            vec![
+                (GCC, "check from NBDB to SBDB (ic)", true),
+                (GCC, "expression parser", false),
+                (GCC, "lex", false),
+                (GCC, "ovn -- 2 LRs connected via LS", false),
+                (GCC, "ovn -- IPv6 prefix delegation", true),
+            ]
+        );
+        assert_eq!(history.recent("x86_64", 1).unwrap().len(), 1);
+
+        let jobs = history.jobs(&Filter::default()).unwrap();
+        assert_eq!(names(&jobs, |job| &job.name), vec![GCC, GCC, CLANG]);
+        assert_eq!(jobs[2].status, "failure");
+        assert_eq!(
+            jobs[2].error.as_deref(),
+            Some(RunnerError::NoSlot.to_string().as_str())
+        );
+        assert_eq!(jobs[0].run.ovn_hash, "aaa");
+
+        let tests = history.tests(&Filter::default()).unwrap();
+        assert_eq!(tests.len(), 6);
+        assert_eq!(tests[2].number, 3);
+        assert_eq!(tests[2].status, "failed");
+        assert_eq!(tests[2].message, "FAILED (ovn-northd.at:58)");
+    }
+
+    #[test]
+    fn filter_conditions() {
+        let (conditions, values) = Filter::default().conditions();
+        assert_eq!(conditions, "");
+        assert!(values.is_empty());
+
+        let filter = Filter::default()
+            .suite("gcc")
+            .test("lex")
+            .since("2024-08-01")
+            .unwrap()
+            .until("2024-08-31")
+            .unwrap();
+        assert!(filter.has_test());
+
+        let (conditions, values) = filter.conditions();
+        assert_eq!(
+            conditions,
+            "WHERE jobs.name LIKE '%' || ? || '%' AND tests.name LIKE '%' || ? || '%' \
+             AND date(runs.start) >= ? AND date(runs.start) <= ?"
+        );
+        assert_eq!(values, vec!["gcc", "lex", "2024-08-01", "2024-08-31"]);
+
+        assert!(matches!(
+            Filter::default().since("08/01/2024"),
+            Err(Error::Date(date)) if date == "08/01/2024"
+        ));
+        assert!(Filter::default().until("2024-02-30").is_err());
+    }
+
+    #[test]
+    fn filter_entries() {
+        let mut history = history();
+        record(
+            &mut history,
+            "aaa",
+            &[
+                runner("gcc", None, Some(LOG)),
+                runner("clang", None, Some(LOG)),
+            ],
+        );
+
+        let jobs = history.jobs(&Filter::default().suite("GCC")).unwrap();
+        assert_eq!(names(&jobs, |job| &job.name), vec![GCC]);
+
+        let tests = history
+            .tests(&Filter::default().suite("Clang").test("ovn --"))
+            .unwrap();
+        assert_eq!(
+            names(&tests, |test| &test.name),
+            vec![
+                "ovn -- 2 LRs connected via LS",
+                "ovn -- IPv6 prefix delegation"
+            ]
+        );
+        assert!(tests.iter().all(|test| test.job == CLANG));
+
+        let day = &jobs[0].run.start[..10];
+        let filter = Filter::default().since(day).unwrap().until(day).unwrap();
+        assert_eq!(history.jobs(&filter).unwrap().len(), 2);
+
+        let filter = Filter::default().until("2000-01-01").unwrap();
+        assert!(history.jobs(&filter).unwrap().is_empty());
+        let filter = Filter::default().since("2999-01-01").unwrap();
+        assert!(history.tests(&filter).unwrap().is_empty());
+    }
+}
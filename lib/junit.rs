@@ -0,0 +1,158 @@
+use std::fs;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::runner::{Finished, Runner};
+use crate::testsuite::{Status, TestSuite};
+use crate::xml::Element;
+
+const JUNIT_FILE: &str = "junit.xml";
+const XML_DECLARATION: &str = "<?xml version='1.0' encoding='UTF-8'?>\n";
+/// Class of the test case standing for a job without test logs.
+const JOB_CLASS: &str = "ovn-ci";
+
+/// Saves "junit.xml" of the job into its log directory.
+pub fn save_job(runner: &Runner<Finished>) -> Result<(), IoError> {
+    save(runner.log_path(), runner.name(), job_suites(runner))
+}
+
+/// Saves "junit.xml" with the suites of all the jobs of the run.
+pub fn save_run<'a, I: Iterator<Item = &'a Runner<Finished>>>(
+    log_path: &Path,
+    runners: I,
+) -> Result<(), IoError> {
+    save(log_path, "OVN CI", runners.flat_map(job_suites).collect())
+}
+
+fn save(log_path: &Path, name: &str, suites: Vec<Suite>) -> Result<(), IoError> {
+    let totals = suites
+        .iter()
+        .fold(Counts::default(), |totals, suite| totals.add(&suite.counts));
+
+    let root = totals
+        .attributes(Element::new("testsuites").attr("name", name))
+        .children(suites.into_iter().map(|suite| suite.element));
+
+    let mut xml = XML_DECLARATION.to_string();
+    root.write(&mut xml, 0);
+
+    fs::write(log_path.join(JUNIT_FILE), xml)
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    tests: usize,
+    failures: usize,
+    skipped: usize,
+    time: Duration,
+}
+
+impl Counts {
+    fn add(self, other: &Counts) -> Self {
+        Counts {
+            tests: self.tests + other.tests,
+            failures: self.failures + other.failures,
+            skipped: self.skipped + other.skipped,
+            time: self.time + other.time,
+        }
+    }
+
+    fn attributes(&self, element: Element) -> Element {
+        element
+            .attr("tests", self.tests)
+            .attr("failures", self.failures)
+            .attr("skipped", self.skipped)
+            .attr("time", seconds(self.time))
+    }
+}
+
+struct Suite {
+    counts: Counts,
+    element: Element,
+}
+
+/// Test suites parsed from the job logs, plus a single test case with
+/// the job result if there are none or the job failed.
+fn job_suites(runner: &Runner<Finished>) -> Vec<Suite> {
+    let mut suites = runner
+        .tests()
+        .iter()
+        .map(|suite| test_suite(runner.name(), suite))
+        .collect::<Vec<_>>();
+
+    if suites.is_empty() || runner.error().is_some() {
+        suites.push(job_suite(runner));
+    }
+
+    suites
+}
+
+fn job_suite(runner: &Runner<Finished>) -> Suite {
+    let counts = Counts {
+        tests: 1,
+        failures: usize::from(!runner.success()),
+        skipped: 0,
+        time: runner.duration(),
+    };
+
+    let mut case = Element::new("testcase")
+        .attr("name", runner.name())
+        .attr("classname", JOB_CLASS)
+        .attr("time", seconds(runner.duration()));
+
+    if let Some(error) = runner.error() {
+        case = case.child(Element::new("failure").attr("message", error));
+    }
+
+    let element = counts
+        .attributes(Element::new("testsuite").attr("name", runner.name()))
+        .child(case);
+
+    Suite { counts, element }
+}
+
+fn test_suite(job: &str, suite: &TestSuite) -> Suite {
+    let mut counts = Counts::default();
+    let mut cases = Vec::with_capacity(suite.cases().len());
+
+    for case in suite.cases() {
+        let time = case.duration().unwrap_or_default();
+        counts.tests += 1;
+        counts.time += time;
+
+        let mut element = Element::new("testcase")
+            .attr("name", format!("{}. {}", case.number(), case.name()))
+            .attr("classname", case.location())
+            .attr("time", seconds(time));
+
+        match case.status() {
+            Status::Passed | Status::ExpectedFailure => {}
+            Status::Skipped => {
+                counts.skipped += 1;
+                element = element.child(Element::new("skipped").attr("message", case.message()));
+            }
+            Status::Failed | Status::UnexpectedPass => {
+                counts.failures += 1;
+
+                let mut failure = Element::new("failure").attr("message", case.message());
+                if let Some(output) = case.output() {
+                    failure = failure.text(output);
+                }
+                element = element.child(failure);
+            }
+        }
+
+        cases.push(element);
+    }
+
+    let element = counts
+        .attributes(Element::new("testsuite").attr("name", format!("{job} - {}", suite.name())))
+        .children(cases);
+
+    Suite { counts, element }
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
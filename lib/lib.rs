@@ -1,12 +1,22 @@
+mod bisect;
 mod ci;
 mod cli_report;
 mod config;
 mod email;
+mod flaky;
 mod git;
+mod history;
+mod junit;
+mod regression;
+mod results;
 mod runner;
 mod scheduler;
+mod testsuite;
 mod util;
 mod vm;
+mod xml;
 
 pub use ci::ContinuousIntegration;
 pub use config::Configuration;
+pub use history::{Filter as HistoryFilter, History};
+pub use vm::{reap_kept, rollback_image};
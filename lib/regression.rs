@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use crate::runner::{Finished, Runner};
+use crate::testsuite::Status;
+use crate::xml::escape;
+
+/// Number of previous runs looked at to tell flaky from regular
+/// status changes.
+pub const HISTORY_WINDOW: usize = 10;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    New,
+    Flaky,
+    Still,
+    Fixed,
+}
+
+impl Change {
+    const ALL: [Change; 4] = [Change::New, Change::Flaky, Change::Still, Change::Fixed];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Change::New => "New failure",
+            Change::Flaky => "Flaky",
+            Change::Still => "Still failing",
+            Change::Fixed => "Fixed",
+        }
+    }
+
+    fn class(&self) -> &'static str {
+        match self {
+            Change::New => "fail",
+            Change::Fixed => "ok",
+            Change::Flaky | Change::Still => "",
+        }
+    }
+}
+
+/// Failures of the jobs and tests of a single run, the tests are
+/// identified by the job and test name as the numbers shift when
+/// tests are added.
+#[derive(Debug, Default)]
+pub struct RunStatus {
+    jobs: HashMap<String, bool>,
+    tests: HashMap<(String, String), bool>,
+}
+
+impl RunStatus {
+    pub fn from_runners<'a, I: Iterator<Item = &'a Runner<Finished>>>(runners: I) -> Self {
+        let mut status = RunStatus::default();
+
+        for runner in runners {
+            status.job(runner.name(), !runner.success());
+            for case in runner.tests().iter().flat_map(|suite| suite.cases()) {
+                status.test(runner.name(), case.name(), case.status());
+            }
+        }
+
+        status
+    }
+
+    /// Job and test name with the failure of every test that ran.
+    pub fn tests(&self) -> impl Iterator<Item = (&str, &str, bool)> {
+        self.tests
+            .iter()
+            .map(|((job, test), failed)| (job.as_str(), test.as_str(), *failed))
+    }
+
+    /// Whether the job failed, `None` if it didn't run.
+    pub fn job_failed(&self, name: &str) -> Option<bool> {
+        self.jobs.get(name).copied()
+    }
+
+    pub fn job(&mut self, name: &str, failed: bool) {
+        *self.jobs.entry(name.to_string()).or_default() |= failed;
+    }
+
+    /// Skipped tests say nothing about the test status.
+    pub fn test(&mut self, job: &str, name: &str, status: Status) {
+        if status != Status::Skipped {
+            *self
+                .tests
+                .entry((job.to_string(), name.to_string()))
+                .or_default() |= status.is_failure();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Regression {
+    job: String,
+    test: Option<String>,
+    change: Change,
+}
+
+/// Job and test statuses compared with the previous runs on the same
+/// arch.
+#[derive(Debug)]
+pub struct Regressions {
+    previous: bool,
+    entries: Vec<Regression>,
+}
+
+impl Regressions {
+    /// `history` holds the previous runs, the most recent first.
+    pub fn new(current: &RunStatus, history: &[RunStatus]) -> Self {
+        let mut entries = Vec::new();
+
+        for (job, failed) in &current.jobs {
+            let statuses = history
+                .iter()
+                .filter_map(|run| run.jobs.get(job).copied())
+                .collect::<Vec<_>>();
+            if let Some(change) = classify(*failed, &statuses) {
+                entries.push(Regression {
+                    job: job.clone(),
+                    test: None,
+                    change,
+                });
+            }
+        }
+
+        for (key, failed) in &current.tests {
+            let statuses = history
+                .iter()
+                .filter_map(|run| run.tests.get(key).copied())
+                .collect::<Vec<_>>();
+            if let Some(change) = classify(*failed, &statuses) {
+                entries.push(Regression {
+                    job: key.0.clone(),
+                    test: Some(key.1.clone()),
+                    change,
+                });
+            }
+        }
+
+        entries.sort_by(|a, b| (&a.job, &a.test).cmp(&(&b.job, &b.test)));
+
+        Regressions {
+            previous: !history.is_empty(),
+            entries,
+        }
+    }
+
+    pub fn count(&self, change: Change) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.change == change)
+            .count()
+    }
+
+    /// Tests of the job that failed for the first time.
+    pub fn new_failures<'a>(&'a self, job: &'a str) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.job == job && entry.change == Change::New)
+            .filter_map(|entry| entry.test.as_deref())
+    }
+
+    /// Anything but the known failures changed.
+    pub fn has_changes(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.change != Change::Still)
+    }
+
+    /// Counts of the classified jobs and tests, e.g.
+    /// "New failure (2) - Still failing (1)".
+    pub fn summary(&self) -> Option<String> {
+        let summary = Change::ALL
+            .iter()
+            .map(|change| (change, self.count(*change)))
+            .filter(|(_, count)| *count > 0)
+            .map(|(change, count)| format!("{} ({count})", change.label()))
+            .collect::<Vec<_>>();
+
+        (!summary.is_empty()).then(|| summary.join(" - "))
+    }
+
+    pub fn report_html(&self) -> String {
+        if !self.previous {
+            return "<p>No previous run on this architecture.</p>".to_string();
+        }
+
+        if self.entries.is_empty() {
+            return "<p>No changes.</p>".to_string();
+        }
+
+        let mut report = "<table><tr><th>Change</th><th>Job</th><th>Test</th></tr>".to_string();
+
+        for change in Change::ALL {
+            for entry in self.entries.iter().filter(|entry| entry.change == change) {
+                report.push_str(&format!(
+                    r#"<tr><td class="{}">{}</td><td>{}</td><td>{}</td></tr>"#,
+                    change.class(),
+                    change.label(),
+                    escape(&entry.job),
+                    entry.test.as_deref().map_or("-".to_string(), escape)
+                ));
+            }
+        }
+
+        report.push_str("</table>");
+        report
+    }
+}
+
+/// Compares the status with the previous known ones, the most recent
+/// first. A change is flaky if the status changed within the history
+/// already.
+fn classify(failed: bool, history: &[bool]) -> Option<Change> {
+    let Some((&previous, older)) = history.split_first() else {
+        return failed.then_some(Change::New);
+    };
+
+    if failed == previous {
+        return failed.then_some(Change::Still);
+    }
+
+    let flipped = older
+        .iter()
+        .scan(previous, |last, &status| {
+            let flipped = status != *last;
+            *last = status;
+            Some(flipped)
+        })
+        .any(|flipped| flipped);
+
+    Some(match (flipped, failed) {
+        (true, _) => Change::Flaky,
+        (false, true) => Change::New,
+        (false, false) => Change::Fixed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_changes() {
+        const F: bool = true;
+        const P: bool = false;
+
+        let cases: &[(bool, &[bool], Option<Change>)] = &[
+            // No history.
+            (F, &[], Some(Change::New)),
+            (P, &[], None),
+            // Same status as the previous run.
+            (F, &[F], Some(Change::Still)),
+            (F, &[F, P, F], Some(Change::Still)),
+            (P, &[P, F, P], None),
+            // Stable history, the status changed now.
+            (F, &[P], Some(Change::New)),
+            (F, &[P, P, P], Some(Change::New)),
+            (P, &[F], Some(Change::Fixed)),
+            (P, &[F, F, F], Some(Change::Fixed)),
+            // The status changed within the history already.
+            (F, &[P, F], Some(Change::Flaky)),
+            (F, &[P, P, F, P], Some(Change::Flaky)),
+            (P, &[F, P], Some(Change::Flaky)),
+            (P, &[F, F, P], Some(Change::Flaky)),
+        ];
+
+        for (failed, history, change) in cases {
+            assert_eq!(
+                classify(*failed, history),
+                *change,
+                "failed: {failed}, history: {history:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn regressions_without_history() {
+        let mut current = RunStatus::default();
+        current.job("ovn GCC [Fedora]", true);
+        current.job("ovn Clang [Fedora]", false);
+        current.test("ovn GCC [Fedora]", "lex", Status::Failed);
+        current.test("ovn GCC [Fedora]", "expression parser", Status::Passed);
+        current.test("ovn GCC [Fedora]", "ovn-bridge-mappings", Status::Skipped);
+
+        let regressions = Regressions::new(&current, &[]);
+        assert_eq!(regressions.count(Change::New), 2);
+        assert_eq!(
+            regressions
+                .new_failures("ovn GCC [Fedora]")
+                .collect::<Vec<_>>(),
+            vec!["lex"]
+        );
+        assert!(regressions.has_changes());
+        assert_eq!(regressions.summary().as_deref(), Some("New failure (2)"));
+        assert_eq!(
+            regressions.report_html(),
+            "<p>No previous run on this architecture.</p>"
+        );
+    }
+}
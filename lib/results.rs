@@ -0,0 +1,188 @@
+use std::fs;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::Suite;
+use crate::runner::{Finished, Runner};
+use crate::vm::ImageVersion;
+
+/// Version of the results format, bumped on every incompatible change.
+pub const SCHEMA_VERSION: u32 = 1;
+const RESULTS_FILE: &str = "results.json";
+
+/// Machine-readable results of the run, saved as "results.json"
+/// next to the HTML report. Paths are relative to the run log
+/// directory.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Results<'a> {
+    schema_version: u32,
+    run: Run<'a>,
+    jobs: Vec<Job<'a>>,
+}
+
+impl<'a> Results<'a> {
+    pub fn new<I: Iterator<Item = &'a Runner<Finished>>>(
+        run: Run<'a>,
+        runners: I,
+        log_path: &Path,
+    ) -> Self {
+        Results {
+            schema_version: SCHEMA_VERSION,
+            run,
+            jobs: runners.map(|runner| Job::new(runner, log_path)).collect(),
+        }
+    }
+
+    pub fn save(&self, log_path: &Path) -> Result<(), IoError> {
+        let json = serde_json::to_string_pretty(self).map_err(IoError::other)?;
+        fs::write(log_path.join(RESULTS_FILE), json)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Run<'a> {
+    ovn_hash: &'a str,
+    ovs_hash: &'a str,
+    arch: &'a str,
+    start: String,
+    end: String,
+    images: &'a [ImageStatus],
+}
+
+impl<'a> Run<'a> {
+    pub fn new(
+        ovn_hash: &'a str,
+        ovs_hash: &'a str,
+        arch: &'a str,
+        start: SystemTime,
+        images: &'a [ImageStatus],
+    ) -> Self {
+        Run {
+            ovn_hash,
+            ovs_hash,
+            arch,
+            start: timestamp(start),
+            end: timestamp(SystemTime::now()),
+            images,
+        }
+    }
+
+    pub fn ovn_hash(&self) -> &str {
+        self.ovn_hash
+    }
+
+    pub fn ovs_hash(&self) -> &str {
+        self.ovs_hash
+    }
+
+    pub fn arch(&self) -> &str {
+        self.arch
+    }
+
+    /// RFC 3339 start of the run.
+    pub fn start(&self) -> &str {
+        &self.start
+    }
+
+    pub fn end(&self) -> &str {
+        &self.end
+    }
+}
+
+/// Base image version the jobs ran on with the update decision.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ImageStatus {
+    #[serde(flatten)]
+    version: ImageVersion,
+    decision: String,
+}
+
+impl ImageStatus {
+    pub fn new(version: ImageVersion, decision: String) -> Self {
+        ImageStatus { version, decision }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} - {}", self.version.describe(), self.decision)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct Job<'a> {
+    name: &'a str,
+    suite: &'a Suite,
+    status: &'static str,
+    error: Option<JobError>,
+    start: String,
+    duration_ms: u128,
+    log: String,
+    artifacts: Vec<String>,
+    backtrace: Option<String>,
+    kept: Option<Kept<'a>>,
+}
+
+impl<'a> Job<'a> {
+    fn new(runner: &'a Runner<Finished>, log_path: &Path) -> Self {
+        let job_path = runner
+            .log_path()
+            .strip_prefix(log_path)
+            .unwrap_or(runner.log_path());
+        let relative = |path: &Path| job_path.join(path).to_string_lossy().to_string();
+
+        Job {
+            name: runner.name(),
+            suite: runner.suite(),
+            status: if runner.success() {
+                "success"
+            } else {
+                "failure"
+            },
+            error: runner.error().map(|error| JobError {
+                kind: error.kind(),
+                message: error.to_string(),
+            }),
+            start: timestamp(runner.started()),
+            duration_ms: runner.duration().as_millis(),
+            log: relative(Path::new("ovn-ci.log")),
+            artifacts: runner
+                .artifacts()
+                .collected()
+                .iter()
+                .map(|path| relative(path))
+                .collect(),
+            backtrace: runner.artifacts().backtrace().map(relative),
+            kept: runner.kept().map(|kept| Kept {
+                endpoint: kept.endpoint(),
+                expires: kept.expires_at(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct JobError {
+    /// Error variant, stable across versions unlike the message.
+    kind: &'static str,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct Kept<'a> {
+    endpoint: &'a str,
+    expires: String,
+}
+
+/// RFC 3339 UTC timestamp used across the run outputs.
+pub fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339()
+}
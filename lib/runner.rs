@@ -1,13 +1,32 @@
 use std::fs::{DirBuilder, File};
 use std::io::{Error as IoError, Write as _};
-use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
-use std::time::{Duration, Instant};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
 
 use thiserror::Error as ThisError;
 
-use crate::config::Suite;
-use crate::vm::{RunnerVm, RunnerVmError};
+use crate::config::{Distro, Profile, Suite, Vm as VmConfig};
+use crate::flaky::FlakeRates;
+use crate::junit;
+use crate::testsuite::{self, TestCase, TestSuite};
+use crate::vm::{
+    Artifacts, GUEST_CORE_DIR, GUEST_WORKSPACE, GroupNetwork, KeptVm, NetworkError, RunnerProcess,
+    RunnerVm, RunnerVmError, Slot, SlotPool, base_image_path, free_hugepages, required_hugepages,
+};
+use crate::xml::escape;
+
+/// Management IPs of the multinode job VMs, the first is the one
+/// running "ci.sh".
+const NODES_ENV: &str = "OVN_CI_NODES";
+/// Additional networks in the order of the guest NICs that follow
+/// the management NIC.
+const NETWORKS_ENV: &str = "OVN_CI_NETWORKS";
+const NODE_DIR_PREFIX: &str = "node-";
+/// Failed tests listed under the job row of the HTML report.
+const MAX_LISTED_TESTS: usize = 50;
+/// Failed tests named in the job summary.
+const MAX_SUMMARY_TESTS: usize = 3;
 
 #[derive(ThisError, Debug)]
 pub enum Error {
@@ -23,12 +42,45 @@ pub enum Error {
     RunnerFinnish(#[source] IoError),
     #[error("Non-zero return code: {0}")]
     ReturnCode(i32),
+    #[error("No free VM slot")]
+    NoSlot,
+    #[error("Cannot read free hugepages: {0}")]
+    HugepagesRead(#[source] IoError),
+    #[error("Not enough free {0} KiB hugepages: {1} required, {2} free")]
+    Hugepages(u64, u64, u64),
+    #[error("Cannot create node network: {0}")]
+    Network(#[source] NetworkError),
+}
+
+impl Error {
+    /// Name of the variant for the machine-readable results.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::LogFile(_) => "log_file",
+            Error::LogWrite(_) => "log_write",
+            Error::LogDirectory(_) => "log_directory",
+            Error::Vm(_) => "vm",
+            Error::RunnerFinnish(_) => "runner_finish",
+            Error::ReturnCode(_) => "return_code",
+            Error::NoSlot => "no_slot",
+            Error::HugepagesRead(_) => "hugepages_read",
+            Error::Hugepages(..) => "hugepages",
+            Error::Network(_) => "network",
+        }
+    }
 }
 
 macro_rules! _runner_error {
     ($e:expr, $self:expr, $start:expr) => {
         $e.map_err(|e| {
-            Runner::<Finished>::new($self.name.clone(), $self.log_path.clone(), $start, Some(e))
+            Box::new(Runner::<Finished>::new(
+                $self.name.clone(),
+                $self.suite.clone(),
+                $self.log_path.clone(),
+                $start,
+                Some(e),
+                Artifacts::default(),
+            ))
         })
     };
 }
@@ -42,44 +94,79 @@ macro_rules! _log_write {
 #[derive(Debug)]
 pub struct New {
     command: Command,
-    vm: RunnerVm,
+    vm_config: VmConfig,
+    distro: Distro,
+    vcpu: usize,
+    profile: Option<Profile>,
+    nodes: usize,
+    networks: Vec<String>,
+    slots: SlotPool,
+    artifacts: Vec<String>,
+    /// Failed VMs are kept unless it's `None`.
+    keep_ttl: Option<Duration>,
+    /// OVN sources replacing the ones of the base image.
+    source: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct Running {
     start: Instant,
-    proc: Child,
+    proc: RunnerProcess,
     vm: RunnerVm,
+    /// The other VMs of a multinode job.
+    peers: Vec<RunnerVm>,
+    artifacts: Vec<String>,
+    keep_ttl: Option<Duration>,
+    networks: Vec<GroupNetwork>,
+    /// Host CPUs the vCPUs are pinned to.
+    cpus: Vec<u32>,
+    hugepages: Option<Hugepages>,
+}
+
+/// Host hugepages backing the VMs of a job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hugepages {
+    /// Page size in KiB.
+    pub size: u64,
+    /// NUMA node the pages come from, any node if unset.
+    pub node: Option<u32>,
+    pub count: u64,
 }
 
 #[derive(Debug)]
 pub struct Finished {
     error: Option<Error>,
+    started: SystemTime,
     duration: Duration,
+    artifacts: Artifacts,
+    kept: Option<KeptVm>,
+    tests: Vec<TestSuite>,
 }
 
 #[derive(Debug)]
 pub struct Runner<S> {
     name: String,
+    suite: Suite,
     log_path: PathBuf,
     state: S,
 }
 
 impl Runner<New> {
     pub fn new(
-        index: usize,
-        memory: u32,
+        vm_config: &VmConfig,
         jobs: usize,
         timeout: &str,
         suite: &Suite,
         log_path: &Path,
+        slots: SlotPool,
+        keep_ttl: Duration,
     ) -> Self {
         let name = suite.name();
 
         let mut log_path = PathBuf::from(log_path);
         log_path.push(
             name.to_lowercase()
-                .replace(['(', ')'], "")
+                .replace(['(', ')', '[', ']'], "")
                 .replace(' ', "_"),
         );
 
@@ -92,15 +179,52 @@ impl Runner<New> {
             .arg(format!("--timeout={timeout}"))
             .envs(suite.envs());
 
-        let vm = RunnerVm::new(index, memory, jobs, log_path.to_string_lossy());
-
         Runner {
             name,
+            suite: suite.clone(),
             log_path,
-            state: New { command, vm },
+            state: New {
+                command,
+                vm_config: vm_config.clone(),
+                distro: suite.distro(),
+                vcpu: jobs,
+                profile: suite
+                    .profile()
+                    .and_then(|profile| vm_config.profile(profile))
+                    .cloned(),
+                nodes: suite.nodes(),
+                networks: suite.networks().to_vec(),
+                slots,
+                artifacts: suite.artifacts(),
+                keep_ttl: Some(keep_ttl),
+                source: None,
+            },
         }
     }
 
+    /// Runs the job on the OVN sources in `path` instead of the ones
+    /// of the base image, the directory has to be named "ovn". Failed
+    /// VMs of such jobs are never kept.
+    pub fn with_source(mut self, path: &str) -> Self {
+        self.state.source = Some(path.to_string());
+        self.state.keep_ttl = None;
+        self
+    }
+
+    /// Selects the tests by autotest keywords in place of the test
+    /// range of the suite.
+    pub fn set_test_keywords(&mut self, keywords: String) {
+        self.state.command.env("TEST_RANGE", keywords);
+    }
+
+    pub fn suite(&self) -> &Suite {
+        &self.suite
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn report_console(&self) -> String {
         format!(
             "The job \"{}\" is starting, log file: {}/ovn-ci.log",
@@ -109,32 +233,187 @@ impl Runner<New> {
         )
     }
 
-    pub fn run(mut self) -> Result<Runner<Running>, Runner<Finished>> {
+    /// Hugepages the VMs of the job require, none if the profile
+    /// doesn't back the guest memory by hugepages.
+    pub fn hugepages(&self) -> Option<Hugepages> {
+        let profile = self.state.profile.as_ref().filter(|p| p.hugepages())?;
+        let size = profile.hugepage_size();
+        let memory = profile.memory().unwrap_or(self.state.vm_config.memory());
+
+        Some(Hugepages {
+            size,
+            node: profile.numa_node(),
+            count: required_hugepages(memory, size) * self.state.nodes as u64,
+        })
+    }
+
+    /// Checks that the host has enough free hugepages for the VM,
+    /// the VM would fail to start otherwise. The `committed` pages
+    /// of the running jobs are not counted as free.
+    pub fn check_hugepages(&self, committed: u64) -> Result<(), Error> {
+        let Some(hugepages) = self.hugepages() else {
+            return Ok(());
+        };
+
+        let free = free_hugepages(hugepages.size, hugepages.node)
+            .map_err(Error::HugepagesRead)?
+            .saturating_sub(committed);
+
+        if free < hugepages.count {
+            return Err(Error::Hugepages(hugepages.size, hugepages.count, free));
+        }
+
+        Ok(())
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.state.nodes
+    }
+
+    /// Host CPUs of the profile cpuset, empty if the vCPUs are not pinned.
+    pub fn cpus(&self) -> Vec<u32> {
+        self.state
+            .profile
+            .as_ref()
+            .map(Profile::cpus)
+            .unwrap_or_default()
+    }
+
+    pub fn has_free_slots(&self) -> bool {
+        self.state.slots.available() >= self.state.nodes
+    }
+
+    /// Finishes the runner without starting it.
+    pub fn fail(self, error: Error) -> Runner<Finished> {
+        Runner::<Finished>::new(
+            self.name,
+            self.suite,
+            self.log_path,
+            Instant::now(),
+            Some(error),
+            Artifacts::default(),
+        )
+    }
+
+    pub fn run(mut self) -> Result<Runner<Running>, Box<Runner<Finished>>> {
         let start = Instant::now();
         let log = _runner_error!(self.create_log_file(&self.log_path), self, start)?;
 
-        _runner_error!(self.state.vm.start().map_err(Error::Vm), self, start)?;
+        let slots = _runner_error!(
+            (0..self.state.nodes)
+                .map(|_| self.state.slots.acquire())
+                .collect::<Option<Vec<_>>>()
+                .ok_or(Error::NoSlot),
+            self,
+            start
+        )?;
+        let networks = _runner_error!(self.create_networks(&slots[0]), self, start)?;
+        let mut vms = _runner_error!(self.create_vms(slots, &networks), self, start)?;
+
+        for vm in &mut vms {
+            _runner_error!(vm.start().map_err(Error::Vm), self, start)?;
+        }
+
+        if vms.len() > 1 {
+            let ips = _runner_error!(
+                vms.iter_mut()
+                    .map(|vm| vm.ip().map(|ip| ip.to_string()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(Error::Vm),
+                self,
+                start
+            )?;
 
-        let proc = _runner_error!(
             self.state
-                .vm
-                .command_spawn(&mut self.state.command, log)
+                .command
+                .env(NODES_ENV, ips.join(" "))
+                .env(NETWORKS_ENV, self.state.networks.join(" "));
+        }
+
+        let mut vm = vms.remove(0);
+        let proc = _runner_error!(
+            vm.command_spawn(&mut self.state.command, log)
                 .map_err(Error::Vm),
             self,
             start
         )?;
+        let cpus = self.cpus();
+        let hugepages = self.hugepages();
 
         Ok(Runner {
             name: self.name,
+            suite: self.suite,
             log_path: self.log_path,
             state: Running {
                 start,
                 proc,
-                vm: self.state.vm,
+                vm,
+                peers: vms,
+                artifacts: self.state.artifacts,
+                keep_ttl: self.state.keep_ttl,
+                networks,
+                cpus,
+                hugepages,
             },
         })
     }
 
+    /// Networks of the multinode job, named after the first slot
+    /// to keep them unique among the running jobs.
+    fn create_networks(&self, slot: &Slot) -> Result<Vec<GroupNetwork>, Error> {
+        self.state
+            .networks
+            .iter()
+            .map(|network| {
+                GroupNetwork::create(format!("{}-{network}", slot.name())).map_err(Error::Network)
+            })
+            .collect()
+    }
+
+    /// VMs of the job, the other nodes log into "node-<index>"
+    /// within the job log directory.
+    fn create_vms(
+        &self,
+        slots: Vec<Slot>,
+        networks: &[GroupNetwork],
+    ) -> Result<Vec<RunnerVm>, Error> {
+        slots
+            .into_iter()
+            .enumerate()
+            .map(|(index, slot)| {
+                let log_path = match index {
+                    0 => self.log_path.clone(),
+                    index => {
+                        let path = self.log_path.join(node_dir(index));
+                        DirBuilder::new()
+                            .create(&path)
+                            .map_err(Error::LogDirectory)?;
+                        path
+                    }
+                };
+
+                let mut vm = RunnerVm::new(
+                    slot,
+                    &self.state.vm_config,
+                    base_image_path(self.state.distro),
+                    self.state.vcpu,
+                    self.state.profile.as_ref(),
+                    log_path.to_string_lossy(),
+                );
+
+                for network in networks {
+                    vm.add_network(network.name());
+                }
+
+                if let Some(source) = &self.state.source {
+                    vm.copy_in(source, GUEST_WORKSPACE);
+                }
+
+                Ok(vm)
+            })
+            .collect()
+    }
+
     fn create_log_file(&self, path: &Path) -> Result<File, Error> {
         DirBuilder::new()
             .create(path)
@@ -179,33 +458,130 @@ impl Runner<Running> {
     }
 
     pub fn finish(mut self) -> Runner<Finished> {
-        if let Err(e) = self.state.vm.retreive_artifacts() {
-            return Runner::<Finished>::new(
-                self.name,
-                self.log_path,
-                self.state.start,
-                Some(Error::Vm(e)),
-            );
-        }
-
         let error = match self.state.proc.wait() {
             Ok(status) if status.success() => None,
             Ok(status) => Some(Error::ReturnCode(status.code().unwrap_or(-1))),
             Err(e) => Some(Error::RunnerFinnish(e)),
         };
 
-        Runner::<Finished>::new(self.name, self.log_path, self.state.start, error)
+        let mut backtrace = None;
+        if error.is_some() && !self.state.vm.is_reachable() {
+            // gdb needs a live guest, the raw cores are still copied offline.
+            eprintln!(
+                "Skipping backtraces of \"{}\", guest unreachable",
+                self.name
+            );
+            self.state.artifacts.push(format!("{GUEST_CORE_DIR}/*"));
+        } else if error.is_some() {
+            match self.state.vm.collect_backtraces() {
+                Ok(Some(summary)) => {
+                    // Keep the cores with the binaries next to the summary.
+                    self.state.artifacts.push(GUEST_CORE_DIR.to_string());
+                    backtrace = Some(summary);
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Couldn't collect core dumps for \"{}\": {e}", self.name),
+            }
+        }
+
+        let mut artifacts = self.state.vm.retrieve_artifacts(&self.state.artifacts);
+        artifacts.set_backtrace(backtrace);
+
+        for (index, peer) in self.state.peers.iter_mut().enumerate() {
+            let node = peer.retrieve_artifacts(&self.state.artifacts);
+            artifacts.merge(node, Path::new(&node_dir(index + 1)));
+        }
+
+        // Multinode groups are torn down together, before their networks.
+        let kept = match (&error, self.state.keep_ttl) {
+            (Some(_), Some(ttl)) if self.state.peers.is_empty() => {
+                self.state.vm.keep(ttl).unwrap_or_else(|e| {
+                    eprintln!("Couldn't keep VM of \"{}\": {e}", self.name);
+                    None
+                })
+            }
+            _ => None,
+        };
+
+        if !self.state.peers.is_empty() {
+            self.state.vm.destroy();
+            self.state.peers.clear();
+            self.state.networks.clear();
+        }
+
+        let mut runner = Runner::<Finished>::new(
+            self.name,
+            self.suite,
+            self.log_path,
+            self.state.start,
+            error,
+            artifacts,
+        );
+        runner.state.kept = kept;
+        runner.state.tests =
+            testsuite::collect(&runner.log_path, runner.state.artifacts.collected());
+
+        if let Err(e) = junit::save_job(&runner) {
+            eprintln!("Couldn't save JUnit report of \"{}\": {e}", runner.name);
+        }
+
+        runner
     }
 }
 
+impl Runner<Running> {
+    pub fn nodes(&self) -> usize {
+        1 + self.state.peers.len()
+    }
+
+    pub fn cpus(&self) -> &[u32] {
+        &self.state.cpus
+    }
+
+    pub fn hugepages(&self) -> Option<Hugepages> {
+        self.state.hugepages
+    }
+}
+
+#[cfg(test)]
 impl Runner<Finished> {
-    fn new(name: String, log_path: PathBuf, start: Instant, error: Option<Error>) -> Self {
+    /// Runner of the suite finished with the parsed tests.
+    pub(crate) fn finished(suite: Suite, error: Option<Error>, tests: Vec<TestSuite>) -> Self {
+        let mut runner = Runner::<Finished>::new(
+            suite.name(),
+            suite,
+            PathBuf::from("/logs/run/job"),
+            Instant::now(),
+            error,
+            Artifacts::default(),
+        );
+        runner.state.tests = tests;
+        runner
+    }
+}
+
+impl Runner<Finished> {
+    fn new(
+        name: String,
+        suite: Suite,
+        log_path: PathBuf,
+        start: Instant,
+        error: Option<Error>,
+        artifacts: Artifacts,
+    ) -> Self {
+        let duration = Instant::now().duration_since(start);
+
         Runner {
             name,
+            suite,
             log_path,
             state: Finished {
                 error,
-                duration: Instant::now().duration_since(start),
+                started: SystemTime::now() - duration,
+                duration,
+                artifacts,
+                kept: None,
+                tests: Vec::new(),
             },
         }
     }
@@ -218,6 +594,67 @@ impl Runner<Finished> {
         &self.name
     }
 
+    pub fn suite(&self) -> &Suite {
+        &self.suite
+    }
+
+    pub fn log_path(&self) -> &Path {
+        &self.log_path
+    }
+
+    pub fn error(&self) -> Option<&Error> {
+        self.state.error.as_ref()
+    }
+
+    pub fn started(&self) -> SystemTime {
+        self.state.started
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.state.duration
+    }
+
+    pub fn artifacts(&self) -> &Artifacts {
+        &self.state.artifacts
+    }
+
+    pub fn kept(&self) -> Option<&KeptVm> {
+        self.state.kept.as_ref()
+    }
+
+    /// Test suites parsed from the autotest logs of the job.
+    pub fn tests(&self) -> &[TestSuite] {
+        &self.state.tests
+    }
+
+    pub fn failed_tests(&self) -> impl Iterator<Item = &TestCase> {
+        self.state.tests.iter().flat_map(TestSuite::failures)
+    }
+
+    /// Compact list of the failed tests, e.g.
+    /// "2 tests failed: 123 ovn -- ECMP, 130 ovn -- NAT".
+    pub fn failed_tests_summary(&self) -> Option<String> {
+        let count = self.failed_tests().count();
+        if count == 0 {
+            return None;
+        }
+
+        let mut summary = format!(
+            "{count} {} failed: {}",
+            if count == 1 { "test" } else { "tests" },
+            self.failed_tests()
+                .take(MAX_SUMMARY_TESTS)
+                .map(|case| format!("{} {}", case.number(), case.name()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if count > MAX_SUMMARY_TESTS {
+            summary.push_str(", ...");
+        }
+
+        Some(summary)
+    }
+
     pub fn report_console(&self) -> String {
         let mut report = format!(
             "The job \"{}\" is done. Duration: {}, Status: ",
@@ -231,25 +668,60 @@ impl Runner<Finished> {
             }
             None => report.push_str("Ok"),
         };
+        if let Some(kept) = self.state.kept.as_ref() {
+            report.push_str(&format!(
+                "\nThe VM is kept for debugging until {}: {}",
+                kept.expires_at(),
+                kept.endpoint()
+            ));
+        }
         report
     }
 
-    pub fn report_html(&self, host: &str, log_prefix: &str) -> String {
+    pub fn report_html(&self, host: &str, log_prefix: &str, flaky: &FlakeRates) -> String {
         let stripped_path = self
             .log_path
             .strip_prefix(log_prefix)
             .unwrap_or(Path::new(""))
             .to_string_lossy();
         let status = if self.success() { "Ok" } else { "Fail" };
-        let artifacts = if self.success() {
-            "-".to_string()
-        } else {
-            format!(
-                r#"<a href="http://{}:8080/{}/logs.tgz" target="_blank">Artifacts</a>"#,
-                host, stripped_path
-            )
-        };
-        format!(
+        let collected = self.state.artifacts.collected();
+        let backtrace = self.state.artifacts.backtrace();
+        let kept = self.state.kept.as_ref();
+        let artifacts =
+            if self.success() || (collected.is_empty() && backtrace.is_none() && kept.is_none()) {
+                "-".to_string()
+            } else {
+                backtrace
+                    .iter()
+                    .map(|path| {
+                        format!(
+                            r#"<a href="http://{}:8080/{}/{}" target="_blank">Backtraces</a>"#,
+                            host,
+                            stripped_path,
+                            path.to_string_lossy()
+                        )
+                    })
+                    .chain(collected.iter().map(|path| {
+                        format!(
+                            r#"<a href="http://{}:8080/{}/{}" target="_blank">{}</a>"#,
+                            host,
+                            stripped_path,
+                            path.to_string_lossy(),
+                            artifact_label(path)
+                        )
+                    }))
+                    .chain(kept.map(|kept| {
+                        format!(
+                            "Kept until {}: <code>{}</code>",
+                            kept.expires_at(),
+                            kept.endpoint()
+                        )
+                    }))
+                    .collect::<Vec<_>>()
+                    .join("<br>")
+            };
+        let mut row = format!(
             r#"<tr><td>{}</td><td class="{}">{}</td><td>{}</td><td><a href="http://{}:8080/{}/ovn-ci.log" target="_blank">Log</a></td><td>{}</td></tr>"#,
             self.name,
             status.to_lowercase(),
@@ -258,7 +730,58 @@ impl Runner<Finished> {
             host,
             stripped_path,
             artifacts
-        )
+        );
+        row.push_str(&self.failed_tests_html(host, &stripped_path, flaky));
+        row
+    }
+
+    fn failed_tests_html(&self, host: &str, stripped_path: &str, flaky: &FlakeRates) -> String {
+        let count = self.failed_tests().count();
+        if count == 0 {
+            return String::new();
+        }
+
+        let mut tests = self
+            .failed_tests()
+            .take(MAX_LISTED_TESTS)
+            .map(|case| {
+                let title = escape(&format!(
+                    "{}. {} ({}): {}",
+                    case.number(),
+                    case.name(),
+                    case.location(),
+                    case.message()
+                ));
+                let badge = flaky
+                    .rate(&self.name, case.name())
+                    .map(|rate| {
+                        format!(
+                            r#" <span class="flaky">known flaky ({:.0}%)</span>"#,
+                            rate * 100.0
+                        )
+                    })
+                    .unwrap_or_default();
+                match case.log() {
+                    Some(log) => format!(
+                        r#"<li><a href="http://{}:8080/{}/{}" target="_blank">{}</a>{}</li>"#,
+                        host,
+                        stripped_path,
+                        log.to_string_lossy(),
+                        title,
+                        badge
+                    ),
+                    None => format!("<li>{title}{badge}</li>"),
+                }
+            })
+            .collect::<String>();
+        if count > MAX_LISTED_TESTS {
+            tests.push_str(&format!(
+                "<li>... and {} more</li>",
+                count - MAX_LISTED_TESTS
+            ));
+        }
+
+        format!(r#"<tr><td colspan="5" class="tests"><ul>{tests}</ul></td></tr>"#)
     }
 
     fn format_duration(&self) -> String {
@@ -268,3 +791,20 @@ impl Runner<Finished> {
         format!("{:02}m {:02}s {:03}ms", minutes, secs % 60, millis % 1000)
     }
 }
+
+fn node_dir(index: usize) -> String {
+    format!("{NODE_DIR_PREFIX}{index}")
+}
+
+/// File name of the artifact, prefixed by the node for the artifacts
+/// of the other multinode VMs.
+fn artifact_label(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    match path.components().next() {
+        Some(Component::Normal(dir)) if dir.to_string_lossy().starts_with(NODE_DIR_PREFIX) => {
+            format!("{}: {name}", dir.to_string_lossy())
+        }
+        _ => name.to_string(),
+    }
+}
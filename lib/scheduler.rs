@@ -5,6 +5,7 @@ use std::time::Duration;
 use crate::Configuration;
 use crate::cli_report::CliReport;
 use crate::runner::{Finished, New, Runner, Running};
+use crate::vm::SlotPool;
 
 #[derive(Debug)]
 pub struct Scheduler {
@@ -13,8 +14,13 @@ pub struct Scheduler {
 }
 
 impl Scheduler {
-    pub fn new(config: &Configuration, log_path: &Path, reporting: Option<CliReport>) -> Self {
-        let mut regular_limit = config.concurrent_limit().unwrap_or(1);
+    pub fn new(
+        config: &Configuration,
+        log_path: &Path,
+        reporting: Option<CliReport>,
+        slots: SlotPool,
+    ) -> Self {
+        let mut regular_limit = config.concurrent_limit();
         let cpu_intensive_limit = if regular_limit > 1 {
             (regular_limit / 4) + 1
         } else {
@@ -26,14 +32,15 @@ impl Scheduler {
         let mut regular = Vec::new();
         let mut cpu_intensive = Vec::new();
 
-        for (i, suite) in config.suites().iter().enumerate() {
+        for suite in config.suites() {
             let runner = Runner::new(
-                i,
-                config.vm().memory(),
+                config.vm(),
                 config.jobs(),
                 config.timeout(),
                 suite,
                 log_path,
+                slots.clone(),
+                config.keep_failed_ttl(),
             );
 
             if cpu_intensive_limit > 0 && suite.is_cpu_intensive() {
@@ -51,8 +58,8 @@ impl Scheduler {
 
     pub fn run(&mut self) {
         while !(self.cpu_itensive.is_finished() && self.regular.is_finished()) {
-            self.cpu_itensive.step();
-            self.regular.step();
+            self.cpu_itensive.step(&self.regular);
+            self.regular.step(&self.cpu_itensive);
 
             if self.cpu_itensive.can_yield() {
                 self.regular.limit += self.cpu_itensive.limit;
@@ -63,9 +70,26 @@ impl Scheduler {
         }
     }
 
+    /// Queues another runner, it starts before the runners of the
+    /// configured suites.
+    pub fn add(&mut self, runner: Runner<New>) {
+        if self.cpu_itensive.limit > 0 && runner.suite().is_cpu_intensive() {
+            self.cpu_itensive.waiting.push(runner);
+        } else {
+            self.regular.waiting.push(runner);
+        }
+    }
+
     pub fn finished(&self) -> impl Iterator<Item = &Runner<Finished>> {
         self.regular.finished().chain(self.cpu_itensive.finished())
     }
+
+    pub fn waiting_mut(&mut self) -> impl Iterator<Item = &mut Runner<New>> {
+        self.regular
+            .waiting
+            .iter_mut()
+            .chain(self.cpu_itensive.waiting.iter_mut())
+    }
 }
 
 #[derive(Debug)]
@@ -90,8 +114,8 @@ impl Queue {
         }
     }
 
-    fn step(&mut self) {
-        self.schedule();
+    fn step(&mut self, other: &Queue) {
+        self.schedule(other);
         self.collect_finished();
     }
 
@@ -107,19 +131,95 @@ impl Queue {
         self.finished.iter()
     }
 
-    fn schedule(&mut self) {
-        while !self.waiting.is_empty() && self.running.len() < self.limit {
-            if let Some(runner) = self.waiting.pop() {
-                println!("{}", runner.report_console());
+    /// Starts the waiting runners from the back of the queue as long as
+    /// their VMs fit into the limit, a multinode runner bigger than the
+    /// limit runs alone on the host, both queues together never exceed
+    /// the concurrent limit. Runners pinned to the same host CPUs never run
+    /// concurrently. A runner without enough free hugepages waits until
+    /// a running job of either queue frees them and fails if there is no
+    /// such job. The pages of a NUMA node committed to the running jobs
+    /// are not free yet while their guests are booting.
+    fn schedule(&mut self, other: &Queue) {
+        let mut index = self.waiting.len();
+
+        while index > 0 {
+            index -= 1;
+
+            if !self.has_capacity(&self.waiting[index], other)
+                || self.has_pinned(&self.waiting[index], other)
+            {
+                continue;
+            }
+
+            let committed = self.committed_hugepages(&self.waiting[index], other);
 
-                match runner.run() {
-                    Ok(runner) => self.running.push(runner),
-                    Err(runner) => self.add_finished(runner),
+            if let Err(e) = self.waiting[index].check_hugepages(committed) {
+                if !self.running.is_empty() || !other.running.is_empty() {
+                    continue;
                 }
+
+                let runner = self.waiting.remove(index);
+                self.add_finished(runner.fail(e));
+                continue;
+            }
+
+            let runner = self.waiting.remove(index);
+            println!("{}", runner.report_console());
+
+            match runner.run() {
+                Ok(runner) => self.running.push(runner),
+                Err(runner) => self.add_finished(*runner),
             }
         }
     }
 
+    fn has_capacity(&self, runner: &Runner<New>, other: &Queue) -> bool {
+        let (used, other_used) = (self.used(), other.used());
+        let nodes = runner.nodes();
+
+        let idle = used == 0 && other_used == 0;
+        // Slots held by kept VMs free up only when a running job ends, with
+        // nothing running the runner starts and fails instead of waiting.
+        let slots = runner.has_free_slots() || idle;
+
+        slots
+            && ((used + nodes <= self.limit
+                && used + other_used + nodes <= self.limit + other.limit)
+                || (self.limit > 0 && idle))
+    }
+
+    /// VMs of the running jobs.
+    fn used(&self) -> usize {
+        self.running.iter().map(Runner::<Running>::nodes).sum()
+    }
+
+    /// Whether a running job of either queue is pinned to some of the
+    /// runner's host CPUs.
+    fn has_pinned(&self, runner: &Runner<New>, other: &Queue) -> bool {
+        let cpus = runner.cpus();
+
+        self.running
+            .iter()
+            .chain(&other.running)
+            .any(|running| running.cpus().iter().any(|cpu| cpus.contains(cpu)))
+    }
+
+    /// Hugepages of the runner's NUMA node the running jobs of either
+    /// queue take, the global pool accounts for them by itself.
+    fn committed_hugepages(&self, runner: &Runner<New>, other: &Queue) -> u64 {
+        let Some(hugepages) = runner.hugepages().filter(|h| h.node.is_some()) else {
+            return 0;
+        };
+
+        self.running
+            .iter()
+            .chain(&other.running)
+            .filter_map(Runner::<Running>::hugepages)
+            .filter(|h| h.size == hugepages.size && h.node == hugepages.node)
+            .map(|h| h.count)
+            .sum()
+    }
+
     fn collect_finished(&mut self) {
         let indexes = self
             .running
@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::DirBuilder;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+const EXTRACT_DIR: &str = "testsuite";
+const LOG_SUFFIX: &str = "testsuite.log";
+/// Per-test directories, kept by autotest only for the failed tests.
+const GROUP_DIR: &str = "testsuite.dir";
+const DETAILED_HEADER: &str = "## Detailed failed tests. ##";
+const GROUP_LOG_START: &str = "-*- compilation -*-";
+/// Failure output is cut to the end of the test log.
+const OUTPUT_TAIL_LINES: usize = 500;
+/// Regular expression characters, the keyword separator and quotes.
+const KEYWORD_SPECIAL: &str = ".[]*^$\\,'!";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    Passed,
+    Skipped,
+    Failed,
+    ExpectedFailure,
+    UnexpectedPass,
+}
+
+impl Status {
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Status::Failed | Status::UnexpectedPass)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Passed => "passed",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+            Status::ExpectedFailure => "expected_failure",
+            Status::UnexpectedPass => "unexpected_pass",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Status> {
+        [
+            Status::Passed,
+            Status::Skipped,
+            Status::Failed,
+            Status::ExpectedFailure,
+            Status::UnexpectedPass,
+        ]
+        .into_iter()
+        .find(|status| status.as_str() == name)
+    }
+}
+
+/// Single test group of the autotest suite.
+#[derive(Debug)]
+pub struct TestCase {
+    number: usize,
+    name: String,
+    location: String,
+    status: Status,
+    message: String,
+    duration: Option<Duration>,
+    output: Option<String>,
+    log: Option<PathBuf>,
+}
+
+impl TestCase {
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// "<file>.at:<line>" of the test definition.
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// Result as reported by autotest, e.g. "FAILED (ovn.at:58)".
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// CPU time of the test, autotest records it only for the tests
+    /// that didn't fail.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    pub fn output(&self) -> Option<&str> {
+        self.output.as_deref()
+    }
+
+    /// Log of the test from "testsuite.dir", relative to the job
+    /// log directory.
+    pub fn log(&self) -> Option<&Path> {
+        self.log.as_deref()
+    }
+
+    /// Parses "<number>. <name> (<file>.at:<line>): <result>" with
+    /// optional "(<user time> <system time>)" of the test children.
+    fn parse(line: &str) -> Option<TestCase> {
+        let (number, rest) = line.split_once(". ")?;
+        let number = number.trim().parse().ok()?;
+
+        let (name, location, result) = rest.match_indices("): ").find_map(|(end, _)| {
+            let start = rest[..end].rfind(" (")?;
+            let location = &rest[start + 2..end];
+            let (file, line) = location.rsplit_once(':')?;
+
+            (file.ends_with(".at") && line.parse::<u32>().is_ok())
+                .then(|| (&rest[..start], location, &rest[end + 3..]))
+        })?;
+
+        let (message, duration) = match result.trim_end().rsplit_once("     (") {
+            Some((message, times)) => (message, parse_times(times.trim_end_matches(')'))),
+            None => (result.trim_end(), None),
+        };
+
+        let status = match message {
+            "ok" => Status::Passed,
+            "UNEXPECTED PASS" => Status::UnexpectedPass,
+            message if message.starts_with("skipped") => Status::Skipped,
+            message if message.starts_with("expected failure") => Status::ExpectedFailure,
+            message if message.starts_with("FAILED") => Status::Failed,
+            _ => return None,
+        };
+
+        Some(TestCase {
+            number,
+            name: name.to_string(),
+            location: location.to_string(),
+            status,
+            message: message.to_string(),
+            duration,
+            output: None,
+            log: None,
+        })
+    }
+}
+
+/// Test cases of a single autotest "testsuite.log".
+#[derive(Debug)]
+pub struct TestSuite {
+    name: String,
+    cases: Vec<TestCase>,
+}
+
+impl TestSuite {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cases(&self) -> &[TestCase] {
+        &self.cases
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &TestCase> {
+        self.cases.iter().filter(|case| case.status.is_failure())
+    }
+
+    /// Links the failed tests to their logs in `group_dir`, named
+    /// by the zero padded test number.
+    fn find_group_logs(&mut self, group_dir: &Path, log_path: &Path) {
+        let Ok(entries) = fs::read_dir(group_dir) else {
+            return;
+        };
+
+        let mut logs = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter_map(|path| {
+                let number = path.file_name()?.to_str()?.parse::<usize>().ok()?;
+                let log = path.join(LOG_SUFFIX);
+                log.is_file().then_some((number, log))
+            })
+            .collect::<HashMap<_, _>>();
+
+        for case in self
+            .cases
+            .iter_mut()
+            .filter(|case| case.status.is_failure())
+        {
+            case.log = logs
+                .remove(&case.number)
+                .map(|log| log.strip_prefix(log_path).unwrap_or(&log).to_path_buf());
+        }
+    }
+
+    pub fn parse(name: &str, log: &str) -> Self {
+        let mut cases = Vec::<TestCase>::new();
+
+        for case in log.lines().filter_map(TestCase::parse) {
+            if !cases.iter().any(|other| other.number == case.number) {
+                cases.push(case);
+            }
+        }
+
+        let mut outputs = group_logs(log);
+        for case in cases.iter_mut().filter(|case| case.status.is_failure()) {
+            case.output = outputs.remove(&case.number);
+        }
+
+        cases.sort_by_key(|case| case.number);
+
+        TestSuite {
+            name: name.to_string(),
+            cases,
+        }
+    }
+}
+
+/// Finds the autotest logs among the collected artifacts, inside the
+/// archives as well, and parses them. The logs from the archives,
+/// including the logs of the failed tests, are extracted into
+/// "testsuite/<archive>" of the job log directory, the nodes of
+/// multinode jobs collect archives of the same name.
+pub fn collect(log_path: &Path, artifacts: &[PathBuf]) -> Vec<TestSuite> {
+    let extract_dir = log_path.join(EXTRACT_DIR);
+    let mut logs = Vec::new();
+
+    for artifact in artifacts {
+        let path = log_path.join(artifact);
+        let name = artifact.to_string_lossy();
+
+        if let Some(stem) = name
+            .strip_suffix(".tgz")
+            .or_else(|| name.strip_suffix(".tar.gz"))
+        {
+            if let Err(e) = extract_logs(&path, &extract_dir.join(stem)) {
+                eprintln!("Couldn't extract test logs from {name}: {e}");
+            }
+        } else if path.is_dir() {
+            find_logs(&path, &mut logs);
+        } else if is_suite_log(&path) {
+            logs.push(path);
+        }
+    }
+
+    find_logs(&extract_dir, &mut logs);
+    logs.sort();
+    logs.dedup();
+
+    logs.iter()
+        .filter_map(|path| {
+            let log = fs::read(path)
+                .inspect_err(|e| eprintln!("Couldn't read {}: {e}", path.to_string_lossy()))
+                .ok()?;
+            let name = path
+                .strip_prefix(&extract_dir)
+                .or_else(|_| path.strip_prefix(log_path))
+                .unwrap_or(path)
+                .to_string_lossy();
+
+            let mut suite = TestSuite::parse(&name, &String::from_utf8_lossy(&log));
+            suite.find_group_logs(&path.with_extension("dir"), log_path);
+
+            Some(suite)
+        })
+        .collect()
+}
+
+fn extract_logs(archive: &Path, extract_dir: &Path) -> Result<(), String> {
+    DirBuilder::new()
+        .recursive(true)
+        .create(extract_dir)
+        .map_err(|e| e.to_string())?;
+
+    // Exits non-zero when nothing matches, that's not an error.
+    Command::new("tar")
+        .arg("-xzf")
+        .arg(archive)
+        .arg("-C")
+        .arg(extract_dir)
+        .arg("--wildcards")
+        .arg(format!("*{LOG_SUFFIX}"))
+        .output()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn find_logs(dir: &Path, logs: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.is_dir() && !path.ends_with(GROUP_DIR) {
+            find_logs(&path, logs);
+        } else if is_suite_log(&path) {
+            logs.push(path);
+        }
+    }
+}
+
+fn is_suite_log(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(LOG_SUFFIX))
+        && !path
+            .components()
+            .any(|component| component.as_os_str() == GROUP_DIR)
+}
+
+/// Logs of the failed tests appended to the end of the main log,
+/// each starts with the compilation mode line and "<number>. ".
+fn group_logs(log: &str) -> HashMap<usize, String> {
+    let Some((_, detailed)) = log.split_once(DETAILED_HEADER) else {
+        return HashMap::new();
+    };
+
+    detailed
+        .split(GROUP_LOG_START)
+        .skip(1)
+        .filter_map(|group| {
+            let group = group.trim_start_matches('\n');
+            let (number, _) = group.split_once(". ")?;
+            let number = number.trim().parse().ok()?;
+
+            let lines = group.lines().collect::<Vec<_>>();
+            // Drop the "#" that starts the next group's mode line.
+            let end = lines
+                .iter()
+                .rposition(|line| line.trim() != "#")
+                .map_or(0, |last| last + 1);
+            let start = end.saturating_sub(OUTPUT_TAIL_LINES);
+
+            Some((number, lines[start..end].join("\n")))
+        })
+        .collect()
+}
+
+/// Autotest options selecting the tests by their names, e.g.
+/// "-k 'lex' -k 'expression parser'", as the numbers shift between
+/// commits. The names are matched as regular expressions against the
+/// test titles, the special characters match any character.
+pub fn select_keywords<S: AsRef<str>>(names: &[S]) -> String {
+    names
+        .iter()
+        .map(|name| format!("-k '{}'", keyword(name.as_ref())))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Autotest option excluding the named tests, e.g.
+/// "-k '!lex,!expression parser'".
+pub fn skip_keywords<S: AsRef<str>>(names: &[S]) -> String {
+    let keywords = names
+        .iter()
+        .map(|name| format!("!{}", keyword(name.as_ref())))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!("-k '{keywords}'")
+}
+
+/// Test name with the characters autotest or the shell would
+/// interpret replaced.
+fn keyword(name: &str) -> String {
+    name.chars()
+        .map(|c| if KEYWORD_SPECIAL.contains(c) { '.' } else { c })
+        .collect()
+}
+
+/// Sums "XmY.YYYs XmY.YYYs" user and system times.
+fn parse_times(times: &str) -> Option<Duration> {
+    times
+        .split_whitespace()
+        .map(|time| {
+            let (minutes, seconds) = time.strip_suffix('s')?.split_once('m')?;
+            let seconds = minutes.parse::<f64>().ok()? * 60.0 + seconds.parse::<f64>().ok()?;
+            Some(Duration::from_secs_f64(seconds))
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = include_str!("testdata/testsuite.log");
+
+    fn assert_secs(duration: Option<Duration>, secs: f64) {
+        assert!((duration.unwrap().as_secs_f64() - secs).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse_case() {
+        let case = TestCase::parse("2. expression parser (ovn.at:118): ok     (1m2.500s 0m0.250s)")
+            .unwrap();
+        assert_eq!(case.number(), 2);
+        assert_eq!(case.name(), "expression parser");
+        assert_eq!(case.location(), "ovn.at:118");
+        assert_eq!(case.status(), Status::Passed);
+        assert_eq!(case.message(), "ok");
+        assert_secs(case.duration(), 62.75);
+
+        let case = TestCase::parse(
+            "3. check from NBDB to SBDB (ic) (ovn-northd.at:45): FAILED (ovn-northd.at:58)",
+        )
+        .unwrap();
+        assert_eq!(case.name(), "check from NBDB to SBDB (ic)");
+        assert_eq!(case.location(), "ovn-northd.at:45");
+        assert_eq!(case.status(), Status::Failed);
+        assert_eq!(case.message(), "FAILED (ovn-northd.at:58)");
+        assert_eq!(case.duration(), None);
+
+        let case = TestCase::parse(
+            "4. ovn-controller - ovn-bridge-mappings (ovn-controller.at:12): skipped (ovn-controller.at:14)",
+        )
+        .unwrap();
+        assert_eq!(case.status(), Status::Skipped);
+
+        let case = TestCase::parse(
+            "5. ovn -- 2 LRs connected via LS (system-ovn.at:7): expected failure (system-ovn.at:9)     (0m0.500s 0m0.100s)",
+        )
+        .unwrap();
+        assert_eq!(case.status(), Status::ExpectedFailure);
+        assert_eq!(case.message(), "expected failure (system-ovn.at:9)");
+        assert_secs(case.duration(), 0.6);
+
+        let case =
+            TestCase::parse("6. ovn -- IPv6 prefix delegation (ovn.at:511): UNEXPECTED PASS")
+                .unwrap();
+        assert_eq!(case.status(), Status::UnexpectedPass);
+    }
+
+    #[test]
+    fn parse_other_lines() {
+        for line in [
+            "1. ovn.at:30: testing lex ...",
+            "./ovn-northd.at:58: exit code was 1, expected 0",
+            "   3: ovn-northd.at:45   check from NBDB to SBDB (ic)",
+            "ERROR: 6 tests were run,",
+        ] {
+            assert!(TestCase::parse(line).is_none(), "{line}");
+        }
+    }
+
+    #[test]
+    fn times() {
+        assert_secs(parse_times("0m0.012s 0m0.008s"), 0.02);
+        assert_secs(parse_times("1m2.500s 0m0.250s"), 62.75);
+        assert_eq!(parse_times("0m0.012s 0.008"), None);
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(
+            select_keywords(&["lex", "check from NBDB to SBDB (ic)"]),
+            "-k 'lex' -k 'check from NBDB to SBDB (ic)'"
+        );
+        assert_eq!(
+            select_keywords(&["ovn -- ARP, ND [v4]", "it's 1.5 * x!"]),
+            "-k 'ovn -- ARP. ND .v4.' -k 'it.s 1.5 . x.'"
+        );
+        assert_eq!(select_keywords::<&str>(&[]), "");
+        assert_eq!(
+            skip_keywords(&["lex", "ovn -- ARP, ND"]),
+            "-k '!lex,!ovn -- ARP. ND'"
+        );
+    }
+
+    #[test]
+    fn detailed_logs() {
+        let logs = group_logs(LOG);
+        assert_eq!(logs.len(), 2);
+
+        let log = &logs[&3];
+        assert!(log.starts_with("3. ovn-northd.at:45: testing check from NBDB to SBDB (ic) ..."));
+        assert!(log.ends_with("FAILED (ovn-northd.at:58)"));
+
+        assert!(logs[&6].ends_with("UNEXPECTED PASS     (0m3.000s 0m1.000s)"));
+        assert!(group_logs("1. lex (ovn.at:30): ok").is_empty());
+    }
+
+    #[test]
+    fn suite() {
+        let suite = TestSuite::parse("testsuite.log", LOG);
+        let statuses = suite
+            .cases()
+            .iter()
+            .map(|case| (case.number(), case.status()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                (1, Status::Passed),
+                (2, Status::Passed),
+                (3, Status::Failed),
+                (4, Status::Skipped),
+                (5, Status::ExpectedFailure),
+                (6, Status::UnexpectedPass),
+            ]
+        );
+
+        let failures = suite.failures().map(TestCase::number).collect::<Vec<_>>();
+        assert_eq!(failures, [3, 6]);
+        assert!(
+            suite.cases()[2]
+                .output()
+                .unwrap()
+                .contains("exit code was 1")
+        );
+        assert!(suite.cases()[0].output().is_none());
+    }
+}
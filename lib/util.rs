@@ -1,4 +1,4 @@
-use std::process::Output;
+use std::process::{Command, Output};
 
 #[macro_export]
 macro_rules! ignore_not_found {
@@ -76,3 +76,29 @@ impl Arch {
         }
     }
 }
+
+/// Renders the command with its environment as a single quoted shell line
+/// that can be passed to ssh or "sh -c".
+pub fn shell_command(command: &Command) -> String {
+    let mut line = String::new();
+
+    for (name, value) in command.get_envs() {
+        line.push_str(&format!(
+            "export {}={}; ",
+            name.to_string_lossy(),
+            shell_quote(&value.unwrap_or_default().to_string_lossy())
+        ));
+    }
+
+    line.push_str(&shell_quote(&command.get_program().to_string_lossy()));
+    for arg in command.get_args() {
+        line.push(' ');
+        line.push_str(&shell_quote(&arg.to_string_lossy()));
+    }
+
+    line
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{Error as IoError, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{Value, json};
+use thiserror::Error as ThisError;
+
+use crate::util::{OutputExt, shell_command};
+
+const AGENT_TIMEOUT: &str = "30";
+const READ_CHUNK: usize = 1024 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SCRIPT_PATH: &str = "/root/ovn-ci-run.sh";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("Cannot execute \"{0}\": {1}")]
+    Command(&'static str, #[source] IoError),
+    #[error("Guest agent command \"{0}\" failed: {1}")]
+    Agent(&'static str, String),
+    #[error("Invalid guest agent response to \"{0}\": {1}")]
+    Response(&'static str, String),
+    #[error("Guest command \"{0}\" didn't finish in time")]
+    Timeout(String),
+    #[error("Cannot write local file: {0}")]
+    LocalFile(#[source] IoError),
+}
+
+/// Thin wrapper around "virsh qemu-agent-command" for a single domain.
+#[derive(Debug, Clone)]
+pub struct Agent {
+    domain: String,
+}
+
+impl Agent {
+    pub fn new(domain: &str) -> Self {
+        Agent {
+            domain: domain.to_string(),
+        }
+    }
+
+    pub fn ping(&self) -> Result<()> {
+        self.execute("guest-ping", json!({})).map(|_| ())
+    }
+
+    /// Runs the command and waits for its output, up to the deadline
+    /// if there is one. The guest process is left behind on timeout.
+    pub fn output(&self, command: &Command, deadline: Option<Instant>) -> Result<Output> {
+        let script = shell_command(command);
+        let pid = self.exec(&script, true)?;
+
+        loop {
+            if let Some(exited) = self.exec_status(pid)? {
+                return Ok(Output {
+                    status: exited.status,
+                    stdout: exited.stdout,
+                    stderr: exited.stderr,
+                });
+            }
+
+            let now = Instant::now();
+            if let Some(deadline) = deadline {
+                if now >= deadline {
+                    return Err(Error::Timeout(script));
+                }
+                thread::sleep(POLL_INTERVAL.min(deadline - now));
+            } else {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    pub fn read_file<W: Write>(&self, path: &str, local: &mut W) -> Result<()> {
+        let handle = self.open(path, "r")?;
+        let result = self.read_to_end(handle, local);
+        self.close(handle)?;
+
+        result
+    }
+
+    pub fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        let handle = self.open(path, "w")?;
+        let result = self
+            .execute(
+                "guest-file-write",
+                json!({"handle": handle, "buf-b64": BASE64.encode(data)}),
+            )
+            .map(|_| ());
+        self.close(handle)?;
+
+        result
+    }
+
+    fn exec(&self, script: &str, capture: bool) -> Result<i64> {
+        let ret = self.execute(
+            "guest-exec",
+            json!({"path": "/bin/sh", "arg": ["-c", script], "capture-output": capture}),
+        )?;
+
+        ret["pid"]
+            .as_i64()
+            .ok_or_else(|| Error::Response("guest-exec", ret.to_string()))
+    }
+
+    fn exec_status(&self, pid: i64) -> Result<Option<Exited>> {
+        let ret = self.execute("guest-exec-status", json!({"pid": pid}))?;
+
+        if !ret["exited"].as_bool().unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let status = match (ret["exitcode"].as_i64(), ret["signal"].as_i64()) {
+            (Some(code), _) => ExitStatus::from_raw((code as i32) << 8),
+            (None, Some(signal)) => ExitStatus::from_raw(signal as i32),
+            (None, None) => return Err(Error::Response("guest-exec-status", ret.to_string())),
+        };
+
+        Ok(Some(Exited {
+            status,
+            stdout: decode(&ret["out-data"])?,
+            stderr: decode(&ret["err-data"])?,
+        }))
+    }
+
+    fn open(&self, path: &str, mode: &str) -> Result<i64> {
+        let ret = self.execute("guest-file-open", json!({"path": path, "mode": mode}))?;
+
+        ret.as_i64()
+            .ok_or_else(|| Error::Response("guest-file-open", ret.to_string()))
+    }
+
+    fn read(&self, handle: i64) -> Result<(Vec<u8>, bool)> {
+        let ret = self.execute(
+            "guest-file-read",
+            json!({"handle": handle, "count": READ_CHUNK}),
+        )?;
+
+        Ok((
+            decode(&ret["buf-b64"])?,
+            ret["eof"].as_bool().unwrap_or(true),
+        ))
+    }
+
+    fn read_to_end<W: Write>(&self, handle: i64, local: &mut W) -> Result<()> {
+        loop {
+            let (data, eof) = self.read(handle)?;
+            local.write_all(&data).map_err(Error::LocalFile)?;
+
+            if eof || data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn close(&self, handle: i64) -> Result<()> {
+        self.execute("guest-file-close", json!({"handle": handle}))
+            .map(|_| ())
+    }
+
+    fn execute(&self, command: &'static str, arguments: Value) -> Result<Value> {
+        let request = json!({"execute": command, "arguments": arguments});
+        let stdout = Command::new("virsh")
+            .arg("qemu-agent-command")
+            .arg("--timeout")
+            .arg(AGENT_TIMEOUT)
+            .arg(&self.domain)
+            .arg(request.to_string())
+            .output()
+            .map_err(|e| Error::Command("virsh-qemu-agent-command", e))?
+            .stdout()
+            .map_err(|e| Error::Agent(command, e.trim_end().to_string()))?;
+
+        let mut response: Value =
+            serde_json::from_str(&stdout).map_err(|e| Error::Response(command, e.to_string()))?;
+
+        Ok(response["return"].take())
+    }
+}
+
+struct Exited {
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Job running in the guest through the agent, the output is redirected
+/// to a file in the guest and streamed into the local log while polling.
+#[derive(Debug)]
+pub struct Process {
+    agent: Agent,
+    pid: i64,
+    log: File,
+    guest_log: String,
+    handle: Option<i64>,
+    status: Option<ExitStatus>,
+    last_poll: Instant,
+}
+
+impl Process {
+    pub fn spawn(agent: Agent, command: &Command, guest_log: &str, log: File) -> Result<Self> {
+        let script = format!(
+            "cd /root && {} > {guest_log} 2>&1\n",
+            shell_command(command)
+        );
+        agent.write_file(SCRIPT_PATH, script.as_bytes())?;
+        let pid = agent.exec(&format!("/bin/sh {SCRIPT_PATH}"), false)?;
+
+        Ok(Process {
+            agent,
+            pid,
+            log,
+            guest_log: guest_log.to_string(),
+            handle: None,
+            status: None,
+            last_poll: Instant::now(),
+        })
+    }
+
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if self.status.is_some() || self.last_poll.elapsed() < POLL_INTERVAL {
+            return Ok(self.status);
+        }
+        self.last_poll = Instant::now();
+
+        // Check the status first so that the final stream
+        // catches everything written before the exit.
+        let status = self
+            .agent
+            .exec_status(self.pid)?
+            .map(|exited| exited.status);
+        self.stream()?;
+
+        if status.is_some() {
+            if let Some(handle) = self.handle.take() {
+                self.agent.close(handle)?;
+            }
+            self.status = status;
+        }
+
+        Ok(status)
+    }
+
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn stream(&mut self) -> Result<()> {
+        let handle = match self.handle {
+            Some(handle) => handle,
+            // The guest log might not exist yet.
+            None => match self.agent.open(&self.guest_log, "r") {
+                Ok(handle) => *self.handle.insert(handle),
+                Err(_) => return Ok(()),
+            },
+        };
+
+        self.agent.read_to_end(handle, &mut self.log)
+    }
+}
+
+fn decode(value: &Value) -> Result<Vec<u8>> {
+    match value.as_str() {
+        Some(data) => BASE64
+            .decode(data)
+            .map_err(|e| Error::Response("base64", e.to_string())),
+        None => Ok(Vec::new()),
+    }
+}
@@ -1,17 +1,42 @@
 use std::fs;
-use std::fs::DirBuilder;
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::fs::{DirBuilder, File};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use thiserror::Error as ThisError;
 
+use crate::config::{CloudImage, ContainerImage, Distro, ExecMode, Image, ImageSource, Mirror};
 use crate::util::{Arch, OutputExt};
-use crate::vm::{BASE_IMAGE, LIB_PATH};
+use crate::vm::{
+    GUEST_CORE_DIR, ImageError, ImageStore, ImageVersion, LIB_PATH, RunnerVm, Slot, SmokeError,
+    SmokeTest, TEST_IMAGE, base_image_log_path, base_image_path, packages,
+};
 use crate::{Configuration, ignore_not_found};
 
 const KICKSTART_NAME: &str = "base.ks";
+const BUILDER_LOG: &str = "/tmp/builder.log";
 const FEDORA_KICKSTART: &str = include_str!("../../vm/fedora.ks.in");
+const CENTOS_STREAM_KICKSTART: &str = include_str!("../../vm/centos-stream.ks.in");
+const FEDORA_REPOS: &str = "\
+repo --name=fedora --mirrorlist=https://mirrors.fedoraproject.org/mirrorlist?repo=fedora-@RELEASE@&arch=@ARCH@
+repo --name=updates --mirrorlist=https://mirrors.fedoraproject.org/mirrorlist?repo=updates-released-f@RELEASE@&arch=@ARCH@";
+const CENTOS_STREAM_MIRROR: &str = "https://mirror.stream.centos.org/@RELEASE@-stream";
+const CENTOS_STREAM_REPOS: &str = "\
+repo --name=baseos --baseurl=https://mirror.stream.centos.org/@RELEASE@-stream/BaseOS/@ARCH@/os/
+repo --name=appstream --baseurl=https://mirror.stream.centos.org/@RELEASE@-stream/AppStream/@ARCH@/os/";
+const QEMU_GA_DROP_IN_DIR: &str = "/etc/systemd/system/qemu-guest-agent.service.d";
+const QEMU_GA_DROP_IN: &str = include_str!("../../vm/qemu-ga.conf");
+const CORE_HELPER_PATH: &str = "/usr/local/libexec/ovn-ci-core";
+const CORE_HELPER: &str = include_str!("../../vm/ovn-ci-core.sh");
+const BASE_IMAGE_SIZE: &str = "10G";
+const MIRROR_REPO_PATH: &str = "/etc/yum.repos.d/ovn-ci-mirror.repo";
+const DISABLE_REPOS: &str = "sed -i 's/^enabled=1/enabled=0/' /etc/yum.repos.d/*.repo";
+/// Packages the kickstart installs that are missing in the cloud images.
+const CLOUD_IMAGE_PACKAGES: &str = "chrony,gdb,glibc-langpack-en,podman,qemu-guest-agent";
+const UBUNTU_CLOUD_IMAGE_PACKAGES: &str = "chrony,gdb,openssh-server,podman,qemu-guest-agent";
+/// Sorted after systemd's "50-coredump.conf" so it takes precedence.
+const CORE_SYSCTL_PATH: &str = "/etc/sysctl.d/99-ovn-ci-core.conf";
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -35,38 +60,96 @@ pub enum Error {
     LogDirectory(#[source] IoError),
     #[error("Cannot retrieve mirror list: {0}")]
     MirrorList(String),
+    #[error("Cannot download cloud image: {0}")]
+    Download(String),
+    #[error("Cloud image checksum mismatch, expected {0}, got {1}")]
+    Checksum(String, String),
+    #[error("Distro \"{0}\" cannot be installed with kickstart")]
+    Unsupported(&'static str),
+    #[error("{0}")]
+    Image(#[from] ImageError),
+    #[error("Cannot get package list: {0}")]
+    PackageList(String),
+    #[error("Smoke test failed: {0}")]
+    SmokeTest(#[from] SmokeError),
 }
 
 #[derive(Debug)]
 pub struct Vm<'a> {
     config: &'a Configuration,
+    image: &'a Image,
     log_path: PathBuf,
     base_image: String,
     kickstart: String,
     arch: Arch,
+    store: ImageStore,
+    candidate: Option<ImageVersion>,
 }
 
 impl<'a> Vm<'a> {
-    pub fn new<P: AsRef<Path>>(config: &'a Configuration, log_path: P) -> Self {
-        let mut log_path = log_path.as_ref().to_path_buf();
-        log_path.push("base-image");
-
+    pub fn new<P: AsRef<Path>>(config: &'a Configuration, image: &'a Image, log_path: P) -> Self {
         Vm {
             config,
-            log_path,
+            image,
+            log_path: base_image_log_path(log_path.as_ref(), image.distro()),
             kickstart: format!("{LIB_PATH}/{KICKSTART_NAME}"),
-            base_image: format!("{LIB_PATH}/{BASE_IMAGE}"),
+            base_image: base_image_path(image.distro()),
             arch: Arch::get(),
+            store: ImageStore::new(image.distro()),
+            candidate: None,
         }
     }
 
+    pub fn current_version(&self) -> Option<ImageVersion> {
+        self.store.current()
+    }
+
+    /// Builds new version of the image, the following update and
+    /// smoke test work with it until it's promoted or discarded.
     pub fn rebuild(&mut self) -> Result<()> {
         self.create_log_dir()?;
 
-        let mirror = self.find_mirror()?;
+        let candidate = self.store.candidate(self.image)?;
+        self.base_image = candidate.path().to_string_lossy().to_string();
+        self.candidate = Some(candidate);
+
+        let image = self.image;
+        match (image.source(), image.cloud_image()) {
+            (ImageSource::CloudImage, Some(cloud_image)) => self.customize_cloud_image(cloud_image),
+            _ => self.install(),
+        }
+    }
 
-        let kickstart = FEDORA_KICKSTART
-            .replace("@RELEASE@", self.config.vm().release())
+    /// Copies the current version to a new one for the update, it's
+    /// promoted or discarded like a rebuilt image.
+    pub fn copy_current(&mut self) -> Result<()> {
+        let candidate = self.store.copy_current(self.image)?;
+        self.base_image = candidate.path().to_string_lossy().to_string();
+        self.candidate = Some(candidate);
+
+        Ok(())
+    }
+
+    fn install(&mut self) -> Result<()> {
+        let (template, os_variant, public_repos) = match self.image.distro() {
+            Distro::Fedora => (FEDORA_KICKSTART, "fedora", FEDORA_REPOS),
+            Distro::CentosStream => (
+                CENTOS_STREAM_KICKSTART,
+                "centos-stream",
+                CENTOS_STREAM_REPOS,
+            ),
+            Distro::Ubuntu => return Err(Error::Unsupported(Distro::Ubuntu.as_str())),
+        };
+        let mirror = match self.image.mirror().and_then(|mirror| mirror.location()) {
+            Some(url) => url.to_string(),
+            None => self.find_mirror()?,
+        };
+        let (source, repos) = self.kickstart_sources(public_repos);
+
+        let kickstart = template
+            .replace("@SOURCE@", &source)
+            .replace("@REPOS@", &repos)
+            .replace("@RELEASE@", self.image.release())
             .replace("@ARCH@", self.arch.target());
 
         fs::write(&self.kickstart, kickstart).map_err(Error::Kickstart)?;
@@ -78,7 +161,7 @@ impl<'a> Vm<'a> {
             .arg("-f")
             .arg("qcow2")
             .arg(&self.base_image)
-            .arg("10G")
+            .arg(BASE_IMAGE_SIZE)
             .output()
             .map_err(|e| Error::Command("qemu-img", e))?
             .status_ok()
@@ -100,7 +183,7 @@ impl<'a> Vm<'a> {
             .arg(format!("path={}", &self.base_image))
             .arg(format!("--location={}", mirror))
             .arg("--os-variant")
-            .arg(format!("fedora{}", self.config.vm().release()))
+            .arg(format!("{os_variant}{}", self.image.release()))
             .arg("--hvm")
             .arg("--graphics=vnc")
             .arg(format!("--initrd-inject={}", &self.kickstart))
@@ -122,6 +205,133 @@ impl<'a> Vm<'a> {
             .map_err(Error::BuildImage)
     }
 
+    /// Creates the base image from a cloud image, this skips
+    /// the installation and takes only a few minutes.
+    fn customize_cloud_image(&mut self, cloud_image: &CloudImage) -> Result<()> {
+        let source = if cloud_image.is_local() {
+            cloud_image.location().to_string()
+        } else {
+            let download = format!(
+                "{LIB_PATH}/cloud-image-{}.qcow2",
+                self.image.distro().as_str()
+            );
+            Command::new("curl")
+                .arg("--location")
+                .arg("--fail")
+                .arg("--silent")
+                .arg("--show-error")
+                .arg("--output")
+                .arg(&download)
+                .arg(cloud_image.location())
+                .output()
+                .map_err(|e| Error::Command("curl", e))?
+                .status_ok()
+                .map_err(Error::Download)?;
+            download
+        };
+
+        self.verify_checksum(&source, cloud_image.checksum())?;
+
+        ignore_not_found!(fs::remove_file(&self.base_image)).map_err(Error::RemoveImage)?;
+
+        match cloud_image.expand() {
+            Some(partition) => {
+                Command::new("qemu-img")
+                    .arg("create")
+                    .arg("-f")
+                    .arg("qcow2")
+                    .arg(&self.base_image)
+                    .arg(BASE_IMAGE_SIZE)
+                    .output()
+                    .map_err(|e| Error::Command("qemu-img", e))?
+                    .status_ok()
+                    .map_err(Error::CreateImage)?;
+
+                Command::new("virt-resize")
+                    .arg("--expand")
+                    .arg(partition)
+                    .arg(&source)
+                    .arg(&self.base_image)
+                    .output()
+                    .map_err(|e| Error::Command("virt-resize", e))?
+                    .status_ok()
+                    .map_err(Error::CreateImage)?;
+            }
+            None => {
+                Command::new("qemu-img")
+                    .arg("convert")
+                    .arg("-O")
+                    .arg("qcow2")
+                    .arg(&source)
+                    .arg(&self.base_image)
+                    .output()
+                    .map_err(|e| Error::Command("qemu-img", e))?
+                    .status_ok()
+                    .map_err(Error::CreateImage)?;
+            }
+        }
+
+        let (packages, ssh_service) = match self.image.distro() {
+            Distro::Fedora | Distro::CentosStream => (CLOUD_IMAGE_PACKAGES, "sshd"),
+            Distro::Ubuntu => (UBUNTU_CLOUD_IMAGE_PACKAGES, "ssh"),
+        };
+
+        let mut command = Command::new("virt-customize");
+        command.arg("-a").arg(&self.base_image);
+
+        // Like the kickstart, the mirror replaces the public repositories.
+        if let Some(mirror) = self.image.mirror() {
+            if mirror.url().is_some() {
+                command.arg("--run-command").arg(DISABLE_REPOS);
+            }
+            command
+                .arg("--write")
+                .arg(format!("{MIRROR_REPO_PATH}:{}", mirror_repo_file(mirror)));
+        }
+
+        command
+            .arg("--install")
+            .arg(packages)
+            .arg("--uninstall")
+            .arg("cloud-init")
+            .arg("--run-command")
+            .arg(format!("systemctl enable {ssh_service} qemu-guest-agent"))
+            .arg("--root-password")
+            .arg("password:123456")
+            .arg("--timezone")
+            .arg("UTC");
+
+        if self.image.distro() != Distro::Ubuntu {
+            command.arg("--selinux-relabel");
+        }
+
+        let output = command
+            .output()
+            .map_err(|e| Error::Command("virt-customize", e))?;
+
+        let mut log_path = self.log_path.clone();
+        log_path.push("cloud-image.log");
+        fs::write(log_path, &output.stdout).map_err(|e| Error::UpdateLog(e.to_string()))?;
+
+        output.status_ok().map_err(Error::BuildImage)
+    }
+
+    fn verify_checksum(&self, path: &str, expected: &str) -> Result<()> {
+        let stdout = Command::new("sha256sum")
+            .arg(path)
+            .output()
+            .map_err(|e| Error::Command("sha256sum", e))?
+            .stdout()
+            .map_err(Error::Download)?;
+        let checksum = stdout.split_whitespace().next().unwrap_or_default();
+
+        if !checksum.eq_ignore_ascii_case(expected) {
+            return Err(Error::Checksum(expected.to_string(), checksum.to_string()));
+        }
+
+        Ok(())
+    }
+
     pub fn update(&mut self) -> Result<()> {
         self.create_log_dir()?;
 
@@ -130,9 +340,9 @@ impl<'a> Vm<'a> {
             .arg("-a")
             .arg(&self.base_image)
             .arg("--delete")
-            .arg("/tmp/builder.log")
+            .arg(BUILDER_LOG)
             .arg("--touch")
-            .arg("/tmp/builder.log")
+            .arg(BUILDER_LOG)
             .arg("--delete")
             .arg("/workspace")
             .arg("--mkdir")
@@ -141,47 +351,177 @@ impl<'a> Vm<'a> {
             .arg(format!("{}:/workspace", self.config.git().ovn_path()))
             .arg("--copy-in")
             .arg(format!("{}:/workspace", self.config.git().ovs_path()))
-            .arg("--delete")
-            .arg("/root/.ssh/authorized_keys")
-            .arg("--ssh-inject")
-            .arg("root:file:/etc/ovn-ci/id_ed25519.pub");
+            .arg("--mkdir")
+            .arg(GUEST_CORE_DIR)
+            .arg("--write")
+            .arg(format!(
+                "{CORE_HELPER_PATH}:{}",
+                CORE_HELPER.replace("@CORE_DIR@", GUEST_CORE_DIR)
+            ))
+            .arg("--chmod")
+            .arg(format!("0755:{CORE_HELPER_PATH}"))
+            .arg("--write")
+            .arg(format!(
+                "{CORE_SYSCTL_PATH}:kernel.core_pattern=|{CORE_HELPER_PATH} %P %e %t\n"
+            ));
 
-        if let Some(image_name) = self.config.image_name() {
-            command
+        match self.config.vm().exec() {
+            ExecMode::Ssh => command
                 .arg("--delete")
-                .arg("/run/containers/storage")
-                .arg("--delete")
-                .arg("/run/libpod")
-                .arg("--run-command")
-                .arg(format!("podman pull {}", image_name))
-                .arg("--run-command")
-                .arg(format!("podman tag {} ovn-org/ovn-tests", image_name))
-                .arg("--run-command")
-                .arg("podman image prune -f");
-        }
+                .arg("/root/.ssh/authorized_keys")
+                .arg("--ssh-inject")
+                .arg("root:file:/etc/ovn-ci/id_ed25519.pub"),
+            ExecMode::Agent => command
+                .arg("--mkdir")
+                .arg(QEMU_GA_DROP_IN_DIR)
+                .arg("--write")
+                .arg(format!(
+                    "{QEMU_GA_DROP_IN_DIR}/ovn-ci.conf:{}",
+                    QEMU_GA_DROP_IN.replace("@QEMU_GA@", qemu_ga_path(self.image.distro()))
+                )),
+        };
+
+        self.customize(&mut command);
 
         command
+            .arg("--run-command")
+            .arg(packages::list_command(self.image.distro()));
+
+        let output = command
             .output()
-            .map_err(|e| Error::Command("virt-customize", e))?
-            .status_ok()
-            .map_err(Error::UpdateImage)?;
+            .map_err(|e| Error::Command("virt-customize", e))?;
 
-        let stdout = Command::new("virt-cat")
+        // Keep the log also when the update failed.
+        let builder_log = Command::new("virt-cat")
             .arg("-a")
             .arg(&self.base_image)
-            .arg("/tmp/builder.log")
+            .arg(BUILDER_LOG)
+            .output()
+            .map_err(|e| Error::Command("virt-cat", e))?;
+
+        let mut log = File::create(self.log_path.join("virt-customize.log"))
+            .map_err(|e| Error::UpdateLog(e.to_string()))?;
+        [&output.stdout, &output.stderr, &builder_log.stdout]
+            .iter()
+            .try_for_each(|data| log.write_all(data))
+            .map_err(|e| Error::UpdateLog(e.to_string()))?;
+
+        output.status_ok().map_err(Error::UpdateImage)?;
+        builder_log.status_ok().map_err(Error::UpdateLog)?;
+
+        let packages = Command::new("virt-cat")
+            .arg("-a")
+            .arg(&self.base_image)
+            .arg(packages::GUEST_PACKAGES_FILE)
             .output()
             .map_err(|e| Error::Command("virt-cat", e))?
             .stdout()
-            .map_err(Error::UpdateLog)?;
+            .map_err(Error::PackageList)?;
 
-        let mut log_path = self.log_path.clone();
-        log_path.push("virt-customize.log");
-        fs::write(log_path, stdout).map_err(|e| Error::UpdateLog(e.to_string()))?;
+        fs::write(self.log_path.join(packages::PACKAGES_FILE), packages)
+            .map_err(|e| Error::PackageList(e.to_string()))?;
 
         Ok(())
     }
 
+    /// Translates the "vm.customize" section into virt-customize
+    /// operations, output of the commands goes to the builder log.
+    fn customize(&self, command: &mut Command) {
+        let customize = self.config.vm().customize();
+
+        if customize.upgrade() {
+            command.arg("--update");
+        }
+
+        if !customize.packages().is_empty() {
+            command.arg("--install").arg(customize.packages().join(","));
+        }
+
+        for file in customize.files() {
+            command
+                .arg("--mkdir")
+                .arg(file.destination())
+                .arg("--copy-in")
+                .arg(format!("{}:{}", file.source(), file.destination()));
+        }
+
+        for script in customize.commands() {
+            command.arg("--run-command").arg(logged(script));
+        }
+
+        let legacy = self
+            .config
+            .image_name()
+            .map(|name| ContainerImage::new(name, Some(TEST_IMAGE)));
+        let images = legacy.iter().chain(customize.images()).collect::<Vec<_>>();
+        if images.is_empty() {
+            return;
+        }
+
+        command
+            .arg("--delete")
+            .arg("/run/containers/storage")
+            .arg("--delete")
+            .arg("/run/libpod");
+
+        for image in images {
+            command
+                .arg("--run-command")
+                .arg(logged(&format!("podman pull {}", image.name())));
+
+            if let Some(tag) = image.tag() {
+                command
+                    .arg("--run-command")
+                    .arg(logged(&format!("podman tag {} {tag}", image.name())));
+            }
+        }
+
+        command
+            .arg("--run-command")
+            .arg(logged("podman image prune -f"));
+    }
+
+    /// Boots VM from an overlay of the image and checks that
+    /// the jobs are able to run in it.
+    pub fn smoke_test(&mut self, slot: Slot) -> Result<()> {
+        let mut vm = RunnerVm::new(
+            slot,
+            self.config.vm(),
+            self.base_image.clone(),
+            self.config.jobs(),
+            None,
+            self.log_path.to_string_lossy(),
+        );
+
+        SmokeTest::new(&mut vm, self.config.vm().min_free_space(), &self.log_path)?.run()?;
+
+        Ok(())
+    }
+
+    /// Makes the rebuilt image the current one and prunes old versions.
+    pub fn promote(&mut self) -> Result<Option<ImageVersion>> {
+        let Some(candidate) = self.candidate.take() else {
+            return Ok(None);
+        };
+
+        self.store.promote(&candidate)?;
+        self.base_image = base_image_path(self.image.distro());
+        self.store.prune(self.config.vm().keep_images())?;
+
+        Ok(Some(candidate))
+    }
+
+    /// Throws away the rebuilt image, the current one stays in use.
+    pub fn discard(&mut self) {
+        if let Some(candidate) = self.candidate.take()
+            && let Err(e) = candidate.remove()
+        {
+            eprintln!("Couldn't remove base image {}: {e}", candidate.version());
+        }
+
+        self.base_image = base_image_path(self.image.distro());
+    }
+
     pub fn destroy(&mut self) {
         if let Err(e) = Command::new("virsh")
             .arg("undefine")
@@ -200,11 +540,45 @@ impl<'a> Vm<'a> {
             .map_err(Error::LogDirectory)
     }
 
+    /// Returns the kickstart installation source and repositories,
+    /// the distro `public_repos` unless a mirror is configured.
+    fn kickstart_sources(&self, public_repos: &str) -> (String, String) {
+        let Some(mirror) = self.image.mirror() else {
+            return (String::new(), public_repos.to_string());
+        };
+
+        // The local ISO is attached as CD-ROM by virt-install.
+        let (source, public) = match mirror.baseurl() {
+            Some(_) if mirror.is_local() => ("cdrom".to_string(), None),
+            Some(url) => (format!("url --url={url}"), None),
+            None => (String::new(), Some(public_repos.to_string())),
+        };
+        let extra = mirror
+            .repos()
+            .iter()
+            .map(|repo| format!("repo --name={} --baseurl={}", repo.name(), repo.baseurl()));
+        let repos = public
+            .into_iter()
+            .chain(extra)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        (source, repos)
+    }
+
     fn find_mirror(&mut self) -> Result<String> {
+        if self.image.distro() == Distro::CentosStream {
+            return Ok(format!(
+                "{}/BaseOS/{}/os/",
+                CENTOS_STREAM_MIRROR.replace("@RELEASE@", self.image.release()),
+                self.arch.target()
+            ));
+        }
+
         let mirrors = Command::new("curl")
             .arg(format!(
                 "https://mirrors.fedoraproject.org/mirrorlist?repo=fedora-{}&arch={}",
-                self.config.vm().release(),
+                self.image.release(),
                 self.arch.target()
             ))
             .output()
@@ -221,8 +595,32 @@ impl<'a> Vm<'a> {
     }
 }
 
+/// Appends output of the guest command to the builder log.
+fn logged(command: &str) -> String {
+    format!("({command}) >> {BUILDER_LOG} 2>&1")
+}
+
 impl Drop for Vm<'_> {
     fn drop(&mut self) {
         self.destroy();
     }
 }
+
+/// Repository file with the mirror tree and the additional repos.
+fn mirror_repo_file(mirror: &Mirror) -> String {
+    mirror
+        .url()
+        .map(|url| ("mirror", url))
+        .into_iter()
+        .chain(mirror.repos().iter().map(|repo| (repo.name(), repo.url())))
+        .map(|(name, url)| format!("[{name}]\nname={name}\nbaseurl={url}\ngpgcheck=0\n"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn qemu_ga_path(distro: Distro) -> &'static str {
+    match distro {
+        Distro::Fedora | Distro::CentosStream => "/usr/bin/qemu-ga",
+        Distro::Ubuntu => "/usr/sbin/qemu-ga",
+    }
+}
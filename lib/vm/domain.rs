@@ -0,0 +1,581 @@
+use thiserror::Error as ThisError;
+
+use crate::xml::Element;
+
+const QEMU_NAMESPACE: &str = "http://libvirt.org/schemas/domain/qemu/1.0";
+const AGENT_CHANNEL: &str = "org.qemu.guest_agent.0";
+/// Targets "vda" to "vdz".
+const MAX_DISKS: usize = 26;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(ThisError, Debug, PartialEq)]
+pub enum Error {
+    #[error("Invalid domain \"{0}\": {1}")]
+    Invalid(String, String),
+}
+
+/// Libvirt domain of a VM, serialized into the domain XML.
+#[derive(Clone, Debug)]
+pub struct Domain {
+    name: String,
+    memory: u32,
+    hugepages: Option<u64>,
+    vcpu: usize,
+    vcpu_pins: Vec<(usize, String)>,
+    numa_node: Option<u32>,
+    cpu: Cpu,
+    arch: String,
+    machine: String,
+    firmware: Option<Firmware>,
+    disks: Vec<Disk>,
+    interfaces: Vec<Interface>,
+    serial_logs: Vec<String>,
+    qemu_args: Vec<String>,
+}
+
+impl Domain {
+    /// Domain with `memory` MiB and `vcpu` CPUs.
+    pub fn new(name: &str, memory: u32, vcpu: usize) -> Self {
+        Domain {
+            name: name.to_string(),
+            memory,
+            hugepages: None,
+            vcpu,
+            vcpu_pins: Vec::new(),
+            numa_node: None,
+            cpu: Cpu::default(),
+            arch: String::new(),
+            machine: String::new(),
+            firmware: None,
+            disks: Vec::new(),
+            interfaces: Vec::new(),
+            serial_logs: Vec::new(),
+            qemu_args: Vec::new(),
+        }
+    }
+
+    pub fn os(mut self, arch: &str, machine: &str) -> Self {
+        self.arch = arch.to_string();
+        self.machine = machine.to_string();
+        self
+    }
+
+    /// UEFI firmware with the variables copied from the `vars` template.
+    pub fn uefi(mut self, code: &str, vars: &str, nvram: &str) -> Self {
+        self.firmware = Some(Firmware {
+            code: code.to_string(),
+            vars: vars.to_string(),
+            nvram: nvram.to_string(),
+        });
+        self
+    }
+
+    /// Backs the memory by hugepages of `size` KiB.
+    pub fn hugepages(mut self, size: u64) -> Self {
+        self.hugepages = Some(size);
+        self
+    }
+
+    pub fn vcpu_pin(mut self, vcpu: usize, cpuset: &str) -> Self {
+        self.vcpu_pins.push((vcpu, cpuset.to_string()));
+        self
+    }
+
+    /// Allocates the memory strictly from the host NUMA node.
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.numa_node = Some(node);
+        self
+    }
+
+    pub fn cpu(mut self, cpu: Cpu) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Adds disk, the first one is the boot disk.
+    pub fn disk(mut self, disk: Disk) -> Self {
+        self.disks.push(disk);
+        self
+    }
+
+    pub fn interface(mut self, interface: Interface) -> Self {
+        self.interfaces.push(interface);
+        self
+    }
+
+    /// Adds serial port logged into `log`, the first one is the console.
+    pub fn serial(mut self, log: &str) -> Self {
+        self.serial_logs.push(log.to_string());
+        self
+    }
+
+    /// Passes the argument directly to QEMU.
+    pub fn qemu_arg(mut self, arg: &str) -> Self {
+        self.qemu_args.push(arg.to_string());
+        self
+    }
+
+    pub fn to_xml(&self) -> Result<String> {
+        self.validate()?;
+
+        let mut xml = String::new();
+        self.element().write(&mut xml, 0);
+
+        Ok(xml)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(Error::Invalid(self.name.clone(), reason));
+
+        if self.name.is_empty() {
+            return invalid("empty name".to_string());
+        }
+
+        if self.memory == 0 || self.vcpu == 0 {
+            return invalid("memory and vCPU count have to be non-zero".to_string());
+        }
+
+        if self.arch.is_empty() || self.machine.is_empty() {
+            return invalid("missing architecture or machine type".to_string());
+        }
+
+        if let Some((vcpu, _)) = self.vcpu_pins.iter().find(|(vcpu, _)| *vcpu >= self.vcpu) {
+            return invalid(format!("pinned vCPU {vcpu} doesn't exist"));
+        }
+
+        if let Some(topology) = &self.cpu.topology
+            && topology.sockets * topology.cores * topology.threads != self.vcpu
+        {
+            return invalid(format!(
+                "CPU topology {}x{}x{} doesn't match {} vCPUs",
+                topology.sockets, topology.cores, topology.threads, self.vcpu
+            ));
+        }
+
+        if self.disks.is_empty() || self.disks.len() > MAX_DISKS {
+            return invalid(format!("1 to {MAX_DISKS} disks required"));
+        }
+
+        Ok(())
+    }
+
+    fn element(&self) -> Element {
+        let mut domain = Element::new("domain").attr("type", "kvm");
+        if !self.qemu_args.is_empty() {
+            domain = domain.attr("xmlns:qemu", QEMU_NAMESPACE);
+        }
+
+        domain = domain
+            .child(Element::new("name").text(&self.name))
+            .child(Element::new("memory").attr("unit", "MiB").text(self.memory));
+
+        if let Some(size) = self.hugepages {
+            domain = domain.child(
+                Element::new("memoryBacking").child(
+                    Element::new("hugepages")
+                        .child(Element::new("page").attr("size", size).attr("unit", "KiB")),
+                ),
+            );
+        }
+
+        domain = domain.child(
+            Element::new("vcpu")
+                .attr("placement", "static")
+                .text(self.vcpu),
+        );
+
+        if !self.vcpu_pins.is_empty() {
+            domain = domain.child(Element::new("cputune").children(self.vcpu_pins.iter().map(
+                |(vcpu, cpuset)| {
+                    Element::new("vcpupin")
+                        .attr("vcpu", vcpu)
+                        .attr("cpuset", cpuset)
+                },
+            )));
+        }
+
+        if let Some(node) = self.numa_node {
+            domain = domain.child(
+                Element::new("numatune").child(
+                    Element::new("memory")
+                        .attr("mode", "strict")
+                        .attr("nodeset", node),
+                ),
+            );
+        }
+
+        domain = domain
+            .child(Element::new("iothreads").text(1))
+            .child(self.os_element())
+            .child(Element::new("features").child(Element::new("acpi")))
+            .child(self.cpu.element())
+            .child(
+                Element::new("clock")
+                    .attr("offset", "utc")
+                    .child(Element::new("timer").attr("name", "kvmclock")),
+            )
+            .child(self.devices_element());
+
+        if !self.qemu_args.is_empty() {
+            domain = domain.child(
+                Element::new("qemu:commandline").children(
+                    self.qemu_args
+                        .iter()
+                        .map(|arg| Element::new("qemu:arg").attr("value", arg)),
+                ),
+            );
+        }
+
+        domain
+    }
+
+    fn os_element(&self) -> Element {
+        let mut os = Element::new("os")
+            .child(
+                Element::new("type")
+                    .attr("arch", &self.arch)
+                    .attr("machine", &self.machine)
+                    .text("hvm"),
+            )
+            .child(Element::new("bootmenu").attr("enable", "no"));
+
+        if let Some(firmware) = &self.firmware {
+            os = os
+                .child(
+                    Element::new("loader")
+                        .attr("readonly", "yes")
+                        .attr("type", "pflash")
+                        .text(&firmware.code),
+                )
+                .child(
+                    Element::new("nvram")
+                        .attr("template", &firmware.vars)
+                        .text(&firmware.nvram),
+                );
+        }
+
+        os
+    }
+
+    fn devices_element(&self) -> Element {
+        let disks = self
+            .disks
+            .iter()
+            .enumerate()
+            .map(|(index, disk)| disk.element(index));
+
+        let serials = self.serial_logs.iter().enumerate().map(|(port, log)| {
+            Element::new("serial")
+                .attr("type", "pty")
+                .child(Element::new("log").attr("file", log))
+                .child(Element::new("target").attr("port", port))
+        });
+
+        let console = self.serial_logs.first().map(|log| {
+            Element::new("console")
+                .attr("type", "pty")
+                .child(Element::new("log").attr("file", log))
+                .child(
+                    Element::new("target")
+                        .attr("type", "serial")
+                        .attr("port", 0),
+                )
+        });
+
+        Element::new("devices")
+            .children(disks)
+            .child(
+                Element::new("controller")
+                    .attr("type", "usb")
+                    .attr("model", "none"),
+            )
+            .children(self.interfaces.iter().map(Interface::element))
+            .child(
+                Element::new("channel")
+                    .attr("type", "unix")
+                    .child(Element::new("source").attr("mode", "bind"))
+                    .child(
+                        Element::new("target")
+                            .attr("type", "virtio")
+                            .attr("name", AGENT_CHANNEL),
+                    ),
+            )
+            .child(Element::new("video").child(Element::new("model").attr("type", "none")))
+            .child(Element::new("memballoon").attr("model", "none"))
+            .child(
+                Element::new("rng").attr("model", "virtio").child(
+                    Element::new("backend")
+                        .attr("model", "random")
+                        .text("/dev/urandom"),
+                ),
+            )
+            .children(serials)
+            .children(console)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Firmware {
+    code: String,
+    vars: String,
+    nvram: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum CpuModel {
+    #[default]
+    HostPassthrough,
+    /// Named QEMU CPU model, e.g. "Skylake-Server".
+    Custom(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Topology {
+    sockets: usize,
+    cores: usize,
+    threads: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cpu {
+    model: CpuModel,
+    topology: Option<Topology>,
+}
+
+impl Cpu {
+    pub fn new(model: CpuModel) -> Self {
+        Cpu {
+            model,
+            topology: None,
+        }
+    }
+
+    pub fn topology(mut self, sockets: usize, cores: usize, threads: usize) -> Self {
+        self.topology = Some(Topology {
+            sockets,
+            cores,
+            threads,
+        });
+        self
+    }
+
+    fn element(&self) -> Element {
+        let cpu = match &self.model {
+            CpuModel::HostPassthrough => Element::new("cpu")
+                .attr("mode", "host-passthrough")
+                .attr("check", "none"),
+            CpuModel::Custom(model) => Element::new("cpu")
+                .attr("mode", "custom")
+                .attr("match", "exact")
+                .attr("check", "none")
+                .child(Element::new("model").attr("fallback", "forbid").text(model)),
+        };
+
+        cpu.children(self.topology.iter().map(|topology| {
+            Element::new("topology")
+                .attr("sockets", topology.sockets)
+                .attr("cores", topology.cores)
+                .attr("threads", topology.threads)
+        }))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Disk {
+    path: String,
+    format: String,
+    serial: Option<String>,
+    readonly: bool,
+}
+
+impl Disk {
+    /// qcow2 disk image at `path`.
+    pub fn new(path: &str) -> Self {
+        Disk {
+            path: path.to_string(),
+            format: "qcow2".to_string(),
+            serial: None,
+            readonly: false,
+        }
+    }
+
+    pub fn format(mut self, format: &str) -> Self {
+        self.format = format.to_string();
+        self
+    }
+
+    /// Serial visible in the guest as "/dev/disk/by-id/virtio-<serial>".
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_string());
+        self
+    }
+
+    pub fn readonly(mut self) -> Self {
+        self.readonly = true;
+        self
+    }
+
+    fn element(&self, index: usize) -> Element {
+        let target = format!("vd{}", (b'a' + index as u8) as char);
+
+        let mut disk = Element::new("disk")
+            .attr("type", "file")
+            .attr("device", "disk")
+            .child(
+                Element::new("driver")
+                    .attr("name", "qemu")
+                    .attr("type", &self.format)
+                    .attr("cache", "none")
+                    .attr("io", "native")
+                    .attr("discard", "unmap")
+                    .attr("iothread", 1),
+            )
+            .child(Element::new("source").attr("file", &self.path))
+            .child(
+                Element::new("target")
+                    .attr("dev", target)
+                    .attr("bus", "virtio"),
+            );
+
+        if index == 0 {
+            disk = disk.child(Element::new("boot").attr("order", 1));
+        }
+
+        if let Some(serial) = &self.serial {
+            disk = disk.child(Element::new("serial").text(serial));
+        }
+
+        if self.readonly {
+            disk = disk.child(Element::new("readonly"));
+        }
+
+        disk
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Interface {
+    network: String,
+    mac: String,
+}
+
+impl Interface {
+    /// Virtio NIC attached to the libvirt `network`.
+    pub fn network(network: &str, mac: &str) -> Self {
+        Interface {
+            network: network.to_string(),
+            mac: mac.to_string(),
+        }
+    }
+
+    fn element(&self) -> Element {
+        Element::new("interface")
+            .attr("type", "network")
+            .child(Element::new("mac").attr("address", &self.mac))
+            .child(Element::new("source").attr("network", &self.network))
+            .child(Element::new("model").attr("type", "virtio"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runner_domain() -> Domain {
+        Domain::new("ovn-ci-vm-0", 4096, 4)
+            .os("x86_64", "q35")
+            .uefi(
+                "/usr/share/OVMF/OVMF_CODE.fd",
+                "/usr/share/OVMF/OVMF_VARS.fd",
+                "/var/lib/ovn-ci/ovn-ci-vm-0_VARS.fd",
+            )
+            .disk(Disk::new("/var/lib/ovn-ci/ovn-ci-vm-0.qcow2").serial("ovn-ci-root-disk"))
+            .interface(Interface::network("ovn-ci-isolated", "52:54:00:00:00:0a"))
+            .serial("/var/log/ovn-ci/job/vm.log")
+    }
+
+    #[test]
+    fn runner() {
+        assert_eq!(
+            runner_domain().to_xml().unwrap(),
+            include_str!("testdata/domain-runner.xml")
+        );
+    }
+
+    #[test]
+    fn full() {
+        let domain = Domain::new("ovn-ci-vm-1", 8192, 4)
+            .os("aarch64", "virt")
+            .hugepages(1048576)
+            .vcpu_pin(0, "2")
+            .vcpu_pin(1, "3")
+            .vcpu_pin(2, "4")
+            .vcpu_pin(3, "5")
+            .numa_node(1)
+            .cpu(Cpu::new(CpuModel::Custom("cortex-a72".to_string())).topology(1, 2, 2))
+            .disk(Disk::new("/var/lib/ovn-ci/ovn-ci-vm-1.qcow2"))
+            .disk(
+                Disk::new("/var/lib/ovn-ci/data.img")
+                    .format("raw")
+                    .serial("data")
+                    .readonly(),
+            )
+            .interface(Interface::network("ovn-ci-isolated", "52:54:00:00:00:0b"))
+            .interface(Interface::network("ovn-ci-data", "52:54:00:00:01:0b"))
+            .serial("/var/log/ovn-ci/job/vm.log")
+            .serial("/var/log/ovn-ci/job/ovs-vswitchd.log")
+            .qemu_arg("-global")
+            .qemu_arg("virtio-net-pci.rx_queue_size=1024");
+
+        assert_eq!(
+            domain.to_xml().unwrap(),
+            include_str!("testdata/domain-full.xml")
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let xml = runner_domain()
+            .qemu_arg("-device 'a<b>&\"c\"'")
+            .to_xml()
+            .unwrap();
+
+        assert!(
+            xml.contains("<qemu:arg value='-device &apos;a&lt;b&gt;&amp;&quot;c&quot;&apos;'/>")
+        );
+        assert!(xml.starts_with(&format!(
+            "<domain type='kvm' xmlns:qemu='{QEMU_NAMESPACE}'>"
+        )));
+    }
+
+    #[test]
+    fn invalid() {
+        let invalid = |domain: Domain, reason: &str| {
+            assert_eq!(
+                domain.to_xml(),
+                Err(Error::Invalid(
+                    "ovn-ci-vm-0".to_string(),
+                    reason.to_string()
+                ))
+            );
+        };
+
+        invalid(
+            runner_domain().vcpu_pin(4, "1"),
+            "pinned vCPU 4 doesn't exist",
+        );
+        invalid(
+            runner_domain().cpu(Cpu::default().topology(1, 2, 1)),
+            "CPU topology 1x2x1 doesn't match 4 vCPUs",
+        );
+        invalid(
+            Domain::new("ovn-ci-vm-0", 4096, 4).os("x86_64", "q35"),
+            "1 to 26 disks required",
+        );
+        invalid(
+            Domain::new("ovn-ci-vm-0", 4096, 4),
+            "missing architecture or machine type",
+        );
+    }
+}
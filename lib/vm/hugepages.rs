@@ -0,0 +1,33 @@
+use std::fs;
+use std::io::Error as IoError;
+
+/// Free host hugepages of `size` KiB, only the NUMA `node` is
+/// considered if set. The pages of the global pool reserved by
+/// running guests, but not faulted in yet, are not free.
+pub fn free(size: u64, node: Option<u32>) -> Result<u64, IoError> {
+    match node {
+        Some(node) => read(&format!(
+            "/sys/devices/system/node/node{node}/hugepages/hugepages-{size}kB/free_hugepages"
+        )),
+        None => {
+            let pool = format!("/sys/kernel/mm/hugepages/hugepages-{size}kB");
+            let free = read(&format!("{pool}/free_hugepages"))?;
+            let reserved = read(&format!("{pool}/resv_hugepages"))?;
+
+            Ok(free.saturating_sub(reserved))
+        }
+    }
+}
+
+fn read(path: &str) -> Result<u64, IoError> {
+    fs::read_to_string(path)
+        .map_err(|e| IoError::new(e.kind(), format!("{path}: {e}")))?
+        .trim()
+        .parse()
+        .map_err(|e| IoError::other(format!("{path}: {e}")))
+}
+
+/// Number of `size` KiB pages backing `memory` MiB.
+pub fn required(memory: u32, size: u64) -> u64 {
+    (u64::from(memory) * 1024).div_ceil(size)
+}
@@ -0,0 +1,273 @@
+use std::fs;
+use std::fs::DirBuilder;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::os::unix;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error as ThisError;
+
+use crate::config::{Distro, Image};
+use crate::ignore_not_found;
+use crate::util::OutputExt;
+use crate::vm::{LIB_PATH, base_image_path};
+
+const IMAGES_DIR: &str = "images";
+const VERSION_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("Cannot access image store: {0}")]
+    Store(#[source] IoError),
+    #[error("Cannot write image metadata: {0}")]
+    Metadata(String),
+    #[error("Cannot promote image {0}: {1}")]
+    Promote(String, #[source] IoError),
+    #[error("Cannot copy image {0}: {1}")]
+    Copy(String, String),
+    #[error("There is no current {0} image")]
+    NoCurrent(&'static str),
+    #[error("There is no {0} image older than the current one")]
+    NoPrevious(&'static str),
+    #[error("Unknown distro \"{0}\"")]
+    UnknownDistro(String),
+}
+
+/// Single build of a base image, stored as
+/// "images/base-<distro>-<version>.qcow2" with metadata next to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ImageVersion {
+    distro: Distro,
+    release: String,
+    version: String,
+    /// Version that was built from scratch, the updated versions are
+    /// copies of it.
+    #[serde(default)]
+    built: Option<String>,
+}
+
+impl ImageVersion {
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn release(&self) -> &str {
+        &self.release
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.file("qcow2")
+    }
+
+    /// Time of the original build, the updates don't make the image
+    /// younger.
+    pub fn created(&self) -> Option<DateTime<Utc>> {
+        NaiveDateTime::parse_from_str(self.build(), VERSION_FORMAT)
+            .ok()
+            .map(|created| created.and_utc())
+    }
+
+    pub fn age(&self) -> Duration {
+        self.created()
+            .and_then(|created| {
+                (DateTime::<Utc>::from(SystemTime::now()) - created)
+                    .to_std()
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Version of the original build the image is an update of.
+    pub fn build(&self) -> &str {
+        self.built.as_deref().unwrap_or(&self.version)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} ({}, {} days old)",
+            self.distro.as_name(),
+            self.release,
+            self.version,
+            self.age().as_secs() / (24 * 60 * 60)
+        )
+    }
+
+    /// Removes the image, used for failed candidates and pruning.
+    pub fn remove(&self) -> Result<()> {
+        ignore_not_found!(fs::remove_file(self.path())).map_err(Error::Store)?;
+        ignore_not_found!(fs::remove_file(self.file("yaml"))).map_err(Error::Store)
+    }
+
+    fn save(&self) -> Result<()> {
+        let yaml = serde_yaml::to_string(self).map_err(|e| Error::Metadata(e.to_string()))?;
+        fs::write(self.file("yaml"), yaml).map_err(|e| Error::Metadata(e.to_string()))
+    }
+
+    fn file(&self, extension: &str) -> PathBuf {
+        images_dir().join(format!(
+            "base-{}-{}.{extension}",
+            self.distro.as_str(),
+            self.version
+        ))
+    }
+}
+
+/// Versions of a distro base image. The base image path is a symlink
+/// to the promoted version, swapped atomically with rename.
+#[derive(Debug)]
+pub struct ImageStore {
+    distro: Distro,
+}
+
+impl ImageStore {
+    pub fn new(distro: Distro) -> Self {
+        ImageStore { distro }
+    }
+
+    /// New version to build the image into, it's not visible
+    /// to the runners until it's promoted.
+    pub fn candidate(&self, image: &Image) -> Result<ImageVersion> {
+        DirBuilder::new()
+            .recursive(true)
+            .create(images_dir())
+            .map_err(Error::Store)?;
+
+        Ok(ImageVersion {
+            distro: self.distro,
+            release: image.release().to_string(),
+            version: DateTime::<Utc>::from(SystemTime::now())
+                .format(VERSION_FORMAT)
+                .to_string(),
+            built: None,
+        })
+    }
+
+    /// New version holding a copy of the current one, the promoted
+    /// versions are never modified.
+    pub fn copy_current(&self, image: &Image) -> Result<ImageVersion> {
+        let current = self
+            .current()
+            .ok_or(Error::NoCurrent(self.distro.as_name()))?;
+        let mut candidate = self.candidate(image)?;
+        candidate.release = current.release.clone();
+        candidate.built = Some(current.built.clone().unwrap_or(current.version.clone()));
+
+        Command::new("cp")
+            .arg("--reflink=auto")
+            .arg("--sparse=always")
+            .arg(current.path())
+            .arg(candidate.path())
+            .output()
+            .map_err(|e| Error::Copy(current.version.clone(), e.to_string()))?
+            .status_ok()
+            .map_err(|e| Error::Copy(current.version.clone(), e))?;
+
+        Ok(candidate)
+    }
+
+    pub fn current(&self) -> Option<ImageVersion> {
+        let target = fs::read_link(base_image_path(self.distro)).ok()?;
+
+        self.versions()
+            .into_iter()
+            .find(|version| version.path() == target)
+    }
+
+    pub fn promote(&self, version: &ImageVersion) -> Result<()> {
+        version.save()?;
+
+        let link = base_image_path(self.distro);
+        let staging = format!("{link}.new");
+        let error = |e| Error::Promote(version.version.clone(), e);
+
+        ignore_not_found!(fs::remove_file(&staging)).map_err(error)?;
+        unix::fs::symlink(version.path(), &staging).map_err(error)?;
+        fs::rename(&staging, &link).map_err(error)
+    }
+
+    /// Keeps the newest update of the `keep` newest builds, the updates
+    /// don't push the previous builds out of the rollback reach. The
+    /// current version is never removed.
+    pub fn prune(&self, keep: usize) -> Result<()> {
+        let current = self.current().map(|version| version.version);
+        let versions = self.versions();
+
+        let mut builds = versions.iter().map(ImageVersion::build).collect::<Vec<_>>();
+        builds.sort_unstable();
+        builds.dedup();
+        let kept_builds = &builds[builds.len().saturating_sub(keep)..];
+
+        let is_newest = |version: &ImageVersion| {
+            versions
+                .iter()
+                .rev()
+                .find(|other| other.build() == version.build())
+                .is_some_and(|newest| newest.version == version.version)
+        };
+
+        versions
+            .iter()
+            .filter(|version| current.as_deref() != Some(version.version()))
+            .filter(|version| !kept_builds.contains(&version.build()) || !is_newest(version))
+            .try_for_each(|version| {
+                println!("Removing old base image {}.", version.describe());
+                version.remove()
+            })
+    }
+
+    /// Points the base image back to the version built before the
+    /// current one.
+    pub fn rollback(&self) -> Result<ImageVersion> {
+        let versions = self.versions();
+        let current = self.current().map(|version| version.version);
+        let older = match current {
+            Some(current) => versions
+                .iter()
+                .take_while(|version| version.version != current)
+                .last(),
+            None => versions.last(),
+        };
+
+        let previous = older.ok_or(Error::NoPrevious(self.distro.as_name()))?;
+        self.promote(previous)?;
+
+        Ok(previous.clone())
+    }
+
+    /// Promoted versions sorted from the oldest.
+    fn versions(&self) -> Vec<ImageVersion> {
+        let Ok(entries) = fs::read_dir(images_dir()) else {
+            return Vec::new();
+        };
+
+        let mut versions = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "yaml"))
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .filter_map(|yaml| serde_yaml::from_str::<ImageVersion>(&yaml).ok())
+            .filter(|version| version.distro == self.distro && version.path().exists())
+            .collect::<Vec<_>>();
+        versions.sort_by(|a, b| a.version.cmp(&b.version));
+
+        versions
+    }
+}
+
+/// Rolls the base image of `distro` back to the previous version.
+pub fn rollback(distro: &str) -> Result<String> {
+    let distro = Distro::from_name(distro).ok_or(Error::UnknownDistro(distro.to_string()))?;
+
+    ImageStore::new(distro)
+        .rollback()
+        .map(|version| version.describe())
+}
+
+fn images_dir() -> PathBuf {
+    PathBuf::from(LIB_PATH).join(IMAGES_DIR)
+}
@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::ignore_not_found;
+use crate::vm::LIB_PATH;
+
+const KEEP_EXTENSION: &str = "keep";
+
+/// Marker of a failed VM that is left running for debugging,
+/// stored next to the VM disk as "<name>.keep".
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct KeptVm {
+    name: String,
+    endpoint: String,
+    log_path: String,
+    expires: u64,
+}
+
+impl KeptVm {
+    pub fn new(name: &str, endpoint: &str, log_path: &str, ttl: Duration) -> Self {
+        KeptVm {
+            name: name.to_string(),
+            endpoint: endpoint.to_string(),
+            log_path: log_path.to_string(),
+            expires: (SystemTime::now() + ttl)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), IoError> {
+        let yaml = serde_yaml::to_string(self).map_err(IoError::other)?;
+        fs::write(marker_path(&self.name), yaml)
+    }
+
+    pub fn remove(&self) -> Result<(), IoError> {
+        ignore_not_found!(fs::remove_file(marker_path(&self.name)))
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn expires_at(&self) -> String {
+        DateTime::from_timestamp(self.expires as i64, 0)
+            .map(|date| date.format("%d %B %Y %H:%M UTC").to_string())
+            .unwrap_or_default()
+    }
+
+    fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        now >= self.expires
+    }
+}
+
+/// Destroys kept VMs whose TTL has passed and forgets the ones that
+/// are not running anymore. Returns names of the VMs that are still
+/// kept, their slots must not be reused.
+pub fn reap() -> HashSet<String> {
+    let mut kept = HashSet::new();
+
+    for vm in kept_vms() {
+        let running = is_running(&vm.name);
+
+        if running && !vm.is_expired() {
+            kept.insert(vm.name);
+            continue;
+        }
+
+        if running {
+            println!("Destroying kept VM \"{}\" (expired).", vm.name);
+            if let Err(e) = Command::new("virsh").arg("destroy").arg(&vm.name).output() {
+                eprintln!("Couldn't destroy kept VM {}: {}", vm.name, e);
+                kept.insert(vm.name);
+                continue;
+            }
+        }
+
+        if let Err(e) = vm.remove() {
+            eprintln!("Couldn't remove keep marker of {}: {}", vm.name, e);
+        }
+    }
+
+    kept
+}
+
+fn kept_vms() -> Vec<KeptVm> {
+    let entries = match fs::read_dir(LIB_PATH) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Couldn't list kept VMs: {e}");
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == KEEP_EXTENSION))
+        .filter_map(|path| {
+            let vm = fs::read_to_string(&path)
+                .ok()
+                .and_then(|yaml| serde_yaml::from_str(&yaml).ok());
+
+            if vm.is_none() {
+                eprintln!("Ignoring invalid keep marker {}", path.to_string_lossy());
+            }
+            vm
+        })
+        .collect()
+}
+
+fn is_running(name: &str) -> bool {
+    Command::new("virsh")
+        .arg("list")
+        .arg("--name")
+        .arg("--state-running")
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line == name)
+        })
+        .unwrap_or(false)
+}
+
+fn marker_path(name: &str) -> PathBuf {
+    PathBuf::from(format!("{LIB_PATH}/{name}.{KEEP_EXTENSION}"))
+}
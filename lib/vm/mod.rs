@@ -1,9 +1,47 @@
+mod agent;
 mod base;
+mod domain;
+mod hugepages;
+mod image;
+mod keep;
+mod network;
+mod packages;
+mod rebuild;
 mod runner;
+mod slot;
+mod smoke;
+
+use std::path::{Path, PathBuf};
+
+use crate::config::Distro;
 
 pub(crate) const LIB_PATH: &str = "/var/lib/ovn-ci";
 
-pub(crate) const BASE_IMAGE: &str = "base.qcow2";
+pub(crate) const GUEST_CORE_DIR: &str = "/var/crash";
+
+/// Guest directory with the OVN and OVS sources.
+pub(crate) const GUEST_WORKSPACE: &str = "/workspace";
+
+/// Container image the jobs run in.
+pub(crate) const TEST_IMAGE: &str = "ovn-org/ovn-tests";
+
+/// Log directory of the distro base image within the run log directory.
+pub(crate) fn base_image_log_path(log_path: &Path, distro: Distro) -> PathBuf {
+    log_path.join("base-image").join(distro.as_str())
+}
+
+/// Symlink to the promoted version of the distro base image.
+pub(crate) fn base_image_path(distro: Distro) -> String {
+    format!("{LIB_PATH}/base-{}.qcow2", distro.as_str())
+}
 
 pub use base::{Error as BaseVmError, Vm as BaseVm};
-pub use runner::{Error as RunnerVmError, Vm as RunnerVm};
+pub use hugepages::{free as free_hugepages, required as required_hugepages};
+pub use image::{Error as ImageError, ImageStore, ImageVersion, rollback as rollback_image};
+pub use keep::{KeptVm, reap as reap_kept};
+pub use network::{AddressPool, Error as NetworkError, GroupNetwork, Network};
+pub use packages::PackageDiff;
+pub use rebuild::{Reason as RebuildReason, SmokeFailures, decide as rebuild_decision};
+pub use runner::{Artifacts, Error as RunnerVmError, Process as RunnerProcess, Vm as RunnerVm};
+pub use slot::{Slot, SlotPool};
+pub use smoke::{Error as SmokeError, SmokeTest};
@@ -0,0 +1,304 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::Error as IoError;
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::rc::Rc;
+
+use thiserror::Error as ThisError;
+
+use crate::config::Network as NetworkConfig;
+use crate::util::OutputExt;
+use crate::vm::LIB_PATH;
+
+pub const NETWORK_NAME: &str = "ovn-ci-isolated";
+const NETWORK_XML: &str = include_str!("../../vm/network.xml");
+const MAC_PREFIX: &str = "52:00:00";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("Cannot execute \"{0}\": {1}")]
+    Command(&'static str, #[source] IoError),
+    #[error("Cannot create network XML: {0}")]
+    NetworkXml(#[source] IoError),
+    #[error("Cannot define network \"{0}\": {1}")]
+    Define(&'static str, String),
+    #[error("Cannot start network \"{0}\": {1}")]
+    Start(&'static str, String),
+    #[error("Cannot remove stale network \"{0}\": {1}")]
+    Remove(&'static str, String),
+    #[error("Cannot create network \"{0}\": {1}")]
+    Create(String, String),
+    #[error("Cannot destroy network \"{0}\": {1}")]
+    Destroy(String, String),
+    #[error("All {0} addresses of the pool are in use")]
+    PoolExhausted(usize),
+}
+
+#[derive(Debug)]
+pub struct Network<'a> {
+    config: &'a NetworkConfig,
+}
+
+impl<'a> Network<'a> {
+    pub fn new(config: &'a NetworkConfig) -> Self {
+        Network { config }
+    }
+
+    /// Makes sure that the isolated network exists, is running and
+    /// matches the configuration, redefining it if it doesn't.
+    pub fn ensure(&self) -> Result<()> {
+        match self.current_xml()? {
+            Some(xml) if self.matches(&xml) => {}
+            Some(_) => {
+                println!("Network \"{NETWORK_NAME}\" doesn't match the configuration, redefining.");
+                self.remove()?;
+                self.define()?;
+            }
+            None => self.define()?,
+        }
+
+        if !self.is_active()? {
+            virsh(&["net-start", NETWORK_NAME], |e| {
+                Error::Start(NETWORK_NAME, e)
+            })?;
+        }
+
+        virsh(&["net-autostart", NETWORK_NAME], |e| {
+            Error::Start(NETWORK_NAME, e)
+        })
+    }
+
+    fn define(&self) -> Result<()> {
+        let xml_path = format!("{LIB_PATH}/{NETWORK_NAME}.xml");
+        fs::write(&xml_path, self.render()).map_err(Error::NetworkXml)?;
+
+        virsh(&["net-define", &xml_path], |e| {
+            Error::Define(NETWORK_NAME, e)
+        })
+    }
+
+    fn remove(&self) -> Result<()> {
+        if self.is_active()? {
+            virsh(&["net-destroy", NETWORK_NAME], |e| {
+                Error::Remove(NETWORK_NAME, e)
+            })?;
+        }
+
+        virsh(&["net-undefine", NETWORK_NAME], |e| {
+            Error::Remove(NETWORK_NAME, e)
+        })
+    }
+
+    fn render(&self) -> String {
+        NETWORK_XML
+            .replace("@NETWORK_NAME@", NETWORK_NAME)
+            .replace("@ADDRESS@", &self.config.address().to_string())
+            .replace("@NETMASK@", &self.config.netmask().to_string())
+            .replace("@DHCP_START@", &self.config.dhcp_start().to_string())
+            .replace("@DHCP_END@", &self.config.dhcp_end().to_string())
+    }
+
+    fn matches(&self, xml: &str) -> bool {
+        let ip = format!(
+            "address='{}' netmask='{}'",
+            self.config.address(),
+            self.config.netmask()
+        );
+        let range = format!(
+            "start='{}' end='{}'",
+            self.config.dhcp_start(),
+            self.config.dhcp_end()
+        );
+
+        xml.contains(&ip) && xml.contains(&range)
+    }
+
+    fn current_xml(&self) -> Result<Option<String>> {
+        let output = Command::new("virsh")
+            .arg("net-dumpxml")
+            .arg("--inactive")
+            .arg(NETWORK_NAME)
+            .output()
+            .map_err(|e| Error::Command("virsh-net-dumpxml", e))?;
+
+        Ok(output.stdout().ok())
+    }
+
+    fn is_active(&self) -> Result<bool> {
+        let stdout = Command::new("virsh")
+            .arg("net-list")
+            .arg("--name")
+            .output()
+            .map_err(|e| Error::Command("virsh-net-list", e))?
+            .stdout()
+            .unwrap_or_default();
+
+        Ok(stdout.lines().any(|line| line.trim() == NETWORK_NAME))
+    }
+}
+
+/// Transient isolated network connecting the nodes of a multinode
+/// job, it's destroyed together with the job.
+#[derive(Debug)]
+pub struct GroupNetwork {
+    name: String,
+}
+
+impl GroupNetwork {
+    pub fn create(name: String) -> Result<Self> {
+        // Leftover of a run that didn't finish cleanly.
+        let _ = Command::new("virsh").arg("net-destroy").arg(&name).output();
+
+        let xml_path = format!("{LIB_PATH}/{name}.xml");
+        fs::write(
+            &xml_path,
+            format!("<network>\n    <name>{name}</name>\n</network>\n"),
+        )
+        .map_err(Error::NetworkXml)?;
+
+        virsh(&["net-create", &xml_path], |e| {
+            Error::Create(name.clone(), e)
+        })?;
+
+        Ok(GroupNetwork { name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for GroupNetwork {
+    fn drop(&mut self) {
+        if let Err(e) = virsh(&["net-destroy", &self.name], |e| {
+            Error::Destroy(self.name.clone(), e)
+        }) {
+            eprintln!("{e}");
+        }
+    }
+}
+
+/// Pool of MAC addresses, one for every address in the DHCP range.
+/// The guest IP is handed out by libvirt DHCP and looked up by MAC.
+#[derive(Debug, Clone)]
+pub struct AddressPool {
+    used: Rc<RefCell<Vec<bool>>>,
+}
+
+impl AddressPool {
+    pub fn new(config: &NetworkConfig) -> Self {
+        AddressPool {
+            used: Rc::new(RefCell::new(vec![false; config.pool_size()])),
+        }
+    }
+
+    pub fn acquire(&self) -> Result<Address> {
+        let mut used = self.used.borrow_mut();
+        let index = used
+            .iter()
+            .position(|used| !used)
+            .ok_or(Error::PoolExhausted(used.len()))?;
+        used[index] = true;
+
+        Ok(Address {
+            index,
+            pool: self.clone(),
+        })
+    }
+
+    fn release(&self, index: usize) {
+        if let Some(used) = self.used.borrow_mut().get_mut(index) {
+            *used = false;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Address {
+    index: usize,
+    pool: AddressPool,
+}
+
+impl Address {
+    pub fn mac(&self) -> String {
+        format!(
+            "{MAC_PREFIX}:{:02x}:{:02x}:{:02x}",
+            (self.index >> 16) & 0xff,
+            (self.index >> 8) & 0xff,
+            self.index & 0xff
+        )
+    }
+
+    /// MAC of the NIC on the `index`th additional network.
+    pub fn network_mac(&self, index: usize) -> String {
+        format!(
+            "52:00:{:02x}:{:02x}:{:02x}:{:02x}",
+            index + 1,
+            (self.index >> 16) & 0xff,
+            (self.index >> 8) & 0xff,
+            self.index & 0xff
+        )
+    }
+
+    /// Looks up the IP for this MAC in the libvirt DHCP leases.
+    pub fn lease_ip(&self) -> Result<Option<Ipv4Addr>> {
+        let stdout = Command::new("virsh")
+            .arg("net-dhcp-leases")
+            .arg(NETWORK_NAME)
+            .arg("--mac")
+            .arg(self.mac())
+            .output()
+            .map_err(|e| Error::Command("virsh-net-dhcp-leases", e))?
+            .stdout()
+            .unwrap_or_default();
+
+        Ok(parse_ip(&stdout, &self.mac()))
+    }
+
+    /// Looks up the IP for this MAC through the guest agent of `domain`.
+    pub fn agent_ip(&self, domain: &str) -> Result<Option<Ipv4Addr>> {
+        let stdout = Command::new("virsh")
+            .arg("domifaddr")
+            .arg(domain)
+            .arg("--source")
+            .arg("agent")
+            .output()
+            .map_err(|e| Error::Command("virsh-domifaddr", e))?
+            .stdout()
+            .unwrap_or_default();
+
+        Ok(parse_ip(&stdout, &self.mac()))
+    }
+}
+
+impl Drop for Address {
+    fn drop(&mut self) {
+        self.pool.release(self.index);
+    }
+}
+
+/// Parses the "<mac> ipv4 <ip>/<prefix>" columns shared by
+/// "virsh net-dhcp-leases" and "virsh domifaddr", the last match wins.
+fn parse_ip(output: &str, mac: &str) -> Option<Ipv4Addr> {
+    output.lines().rev().find_map(|line| {
+        let columns = line.split_whitespace().collect::<Vec<_>>();
+        let index = columns.iter().position(|column| *column == mac)?;
+
+        match columns.get(index + 1..index + 3) {
+            Some(["ipv4", address]) => address.split('/').next()?.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+fn virsh<F: FnOnce(String) -> Error>(args: &[&str], error: F) -> Result<()> {
+    Command::new("virsh")
+        .args(args)
+        .output()
+        .map_err(|e| Error::Command("virsh", e))?
+        .status_ok()
+        .map_err(error)
+}
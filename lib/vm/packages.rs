@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Distro;
+
+pub const PACKAGES_FILE: &str = "packages.txt";
+pub const GUEST_PACKAGES_FILE: &str = "/tmp/ovn-ci-packages.txt";
+/// Entry of the newest installed kernel, what "uname -r" reports after boot.
+const KERNEL_ENTRY: &str = "uname-r";
+
+/// Shell command listing "<name> <version>" of every installed package
+/// and the kernel into the guest packages file.
+pub fn list_command(distro: Distro) -> String {
+    let query = match distro {
+        Distro::Fedora | Distro::CentosStream => {
+            r"rpm -qa --qf '%{NAME}.%{ARCH} %{VERSION}-%{RELEASE}\n'"
+        }
+        Distro::Ubuntu => r"dpkg-query -W -f '${Package}:${Architecture} ${Version}\n'",
+    };
+
+    format!(
+        "{{ {query}; echo \"{KERNEL_ENTRY} $(ls /lib/modules | sort -V | tail -n 1)\"; }} | sort > {GUEST_PACKAGES_FILE}"
+    )
+}
+
+/// Difference between the package lists of two runs.
+#[derive(Debug)]
+pub struct PackageDiff {
+    distro: Distro,
+    previous: bool,
+    added: Vec<(String, String)>,
+    removed: Vec<(String, String)>,
+    changed: Vec<(String, String, String)>,
+}
+
+impl PackageDiff {
+    /// Compares the lists stored in the base image log directories.
+    pub fn new(distro: Distro, previous: &Path, current: &Path) -> Self {
+        let current = read_list(&current.join(PACKAGES_FILE)).unwrap_or_default();
+        let previous = read_list(&previous.join(PACKAGES_FILE));
+        let mut diff = PackageDiff {
+            distro,
+            previous: previous.is_some(),
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+
+        let Some(previous) = previous else {
+            return diff;
+        };
+
+        for (name, version) in &current {
+            match previous.get(name) {
+                None => diff.added.push((name.clone(), version.clone())),
+                Some(old) if old != version => {
+                    diff.changed
+                        .push((name.clone(), old.clone(), version.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        diff.removed = previous
+            .into_iter()
+            .filter(|(name, _)| !current.contains_key(name))
+            .collect();
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn report_html(&self) -> String {
+        let mut report = format!("<h4>{}</h4>", self.distro.as_name());
+
+        if !self.previous {
+            report.push_str("<p>No package list of the previous run.</p>");
+            return report;
+        }
+
+        if self.is_empty() {
+            report.push_str("<p>No package changes.</p>");
+            return report;
+        }
+
+        report.push_str("<table><tr><th>Package</th><th>Previous</th><th>Current</th></tr>");
+
+        let rows = self
+            .changed
+            .iter()
+            .map(|(name, old, new)| (name, old.as_str(), new.as_str()))
+            .chain(
+                self.added
+                    .iter()
+                    .map(|(name, new)| (name, "-", new.as_str())),
+            )
+            .chain(
+                self.removed
+                    .iter()
+                    .map(|(name, old)| (name, old.as_str(), "-")),
+            );
+
+        for (name, old, new) in rows {
+            report.push_str(&format!(
+                "<tr><td>{name}</td><td>{old}</td><td>{new}</td></tr>"
+            ));
+        }
+
+        report.push_str("</table>");
+        report
+    }
+}
+
+/// Installonly packages, e.g. the kernels, are installed in several
+/// versions, those are compared as a set.
+fn read_list(path: &Path) -> Option<BTreeMap<String, String>> {
+    let list = fs::read_to_string(path).ok()?;
+
+    let mut versions = BTreeMap::<String, Vec<&str>>::new();
+    for (name, version) in list.lines().filter_map(|line| line.split_once(' ')) {
+        versions.entry(name.to_string()).or_default().push(version);
+    }
+
+    Some(
+        versions
+            .into_iter()
+            .map(|(name, mut versions)| {
+                versions.sort_unstable();
+                (name, versions.join(", "))
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,121 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::ErrorKind as IoErrorKind;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Datelike, Utc};
+
+use crate::config::{Distro, Image, Rebuild};
+use crate::ignore_not_found;
+use crate::vm::{ImageVersion, LIB_PATH};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Reason {
+    Missing,
+    Forced,
+    Scheduled(u32),
+    MaxAge(u64),
+    ReleaseChanged(String, String),
+    SmokeFailures(u32),
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Reason::Missing => write!(f, "there is no image"),
+            Reason::Forced => write!(f, "forced from command line"),
+            Reason::Scheduled(day) => write!(f, "scheduled on day {day}"),
+            Reason::MaxAge(days) => write!(f, "older than {days} days"),
+            Reason::ReleaseChanged(old, new) => write!(f, "release changed from {old} to {new}"),
+            Reason::SmokeFailures(count) => write!(f, "{count} consecutive smoke test failures"),
+        }
+    }
+}
+
+/// Decides according to the policy whether the image should be
+/// rebuilt, `None` means that the current image is kept.
+pub fn decide(
+    policy: &Rebuild,
+    image: &Image,
+    current: Option<&ImageVersion>,
+    forced: bool,
+) -> Option<Reason> {
+    let Some(current) = current else {
+        return Some(Reason::Missing);
+    };
+
+    if forced {
+        return Some(Reason::Forced);
+    }
+
+    // Rebuild only once on the scheduled day.
+    let today = DateTime::<Utc>::from(SystemTime::now()).date_naive();
+    let built_today = current
+        .created()
+        .is_some_and(|created| created.date_naive() == today);
+    if policy.is_scheduled(today.day()) && !built_today {
+        return Some(Reason::Scheduled(today.day()));
+    }
+
+    if let Some(max_age) = policy.max_age()
+        && current.age() > max_age
+    {
+        return Some(Reason::MaxAge(max_age.as_secs() / DAY_SECS));
+    }
+
+    if policy.on_release_change() && current.release() != image.release() {
+        return Some(Reason::ReleaseChanged(
+            current.release().to_string(),
+            image.release().to_string(),
+        ));
+    }
+
+    let failures = SmokeFailures::new(image.distro()).count();
+    if policy
+        .smoke_failures()
+        .is_some_and(|limit| failures >= limit)
+    {
+        return Some(Reason::SmokeFailures(failures));
+    }
+
+    None
+}
+
+/// Number of consecutive smoke test failures of the current image,
+/// persisted across runs.
+#[derive(Debug)]
+pub struct SmokeFailures {
+    path: String,
+}
+
+impl SmokeFailures {
+    pub fn new(distro: Distro) -> Self {
+        SmokeFailures {
+            path: format!("{LIB_PATH}/smoke-failures-{}", distro.as_str()),
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|count| count.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn record(&self) -> u32 {
+        let count = self.count() + 1;
+        if let Err(e) = fs::write(&self.path, count.to_string()) {
+            eprintln!("Couldn't record smoke test failure: {e}");
+        }
+
+        count
+    }
+
+    pub fn reset(&self) {
+        if let Err(e) = ignore_not_found!(fs::remove_file(&self.path)) {
+            eprintln!("Couldn't reset smoke test failures: {e}");
+        }
+    }
+}
@@ -1,18 +1,26 @@
-use std::ffi::OsStr;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::fs;
-use std::fs::File;
+use std::fs::{DirBuilder, File};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
-use std::process::{Child, Command, Output};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use thiserror::Error as ThisError;
 
+use crate::config::{DEFAULT_ARTIFACT, ExecMode, Profile, Ready, Vm as VmConfig};
 use crate::ignore_not_found;
-use crate::util::{Arch, OutputExt};
-use crate::vm::{BASE_IMAGE, LIB_PATH};
+use crate::util::{Arch, OutputExt, shell_command};
+use crate::vm::agent::{Agent, Error as AgentError, Process as AgentProcess};
+use crate::vm::domain::{Cpu, CpuModel, Disk, Domain, Error as DomainError, Interface};
+use crate::vm::keep::KeptVm;
+use crate::vm::network::Error as NetworkError;
+use crate::vm::network::NETWORK_NAME;
+use crate::vm::{GUEST_CORE_DIR, LIB_PATH, Slot};
 
-pub const VM_XML: &str = include_str!("../../vm/vm.xml");
-pub const VM_PREFIX: &str = "ovn-ci-vm";
-pub const NET_SUFFIX_OFFSET: usize = 10;
 #[cfg(target_arch = "aarch64")]
 pub const UEFI_CODE: &str = "/usr/share/AAVMF/AAVMF_CODE.fd";
 #[cfg(target_arch = "aarch64")]
@@ -22,18 +30,35 @@ pub const UEFI_CODE: &str = "/usr/share/OVMF/OVMF_CODE.fd";
 #[cfg(target_arch = "x86_64")]
 pub const UEFI_VARS: &str = "/usr/share/OVMF/OVMF_VARS.fd";
 pub const READY_STRING: &str = "Ready!";
-const SSH_COMMON_ARGUMENTS: [&str; 11] = [
+const GUEST_LOG: &str = "/root/ovn-ci.log";
+const GUEST_ARCHIVE: &str = "/tmp/ovn-ci-artifact.tgz";
+const ARTIFACTS_DIR: &str = "artifacts";
+const ARTIFACTS_MANIFEST: &str = "artifacts.txt";
+const BACKTRACE_FILE: &str = "backtrace.txt";
+/// Units that failed in a degraded, but otherwise running guest.
+const FAILED_UNITS_FILE: &str = "failed-units.txt";
+const CONSOLE_TAIL_LINES: usize = 50;
+const SSH_KEY: &str = "/etc/ovn-ci/id_ed25519";
+const SSH_COMMON_ARGUMENTS: [&str; 7] = [
     "-4",
     "-i",
-    "/etc/ovn-ci/id_ed25519",
+    SSH_KEY,
     "-o",
     "UserKnownHostsFile=/dev/null",
     "-o",
     "StrictHostKeyChecking=no",
+];
+const SSH_CONNECT_ARGUMENTS: [&str; 4] = ["-o", "ConnectTimeout=60", "-o", "ConnectionAttempts=60"];
+/// Single short attempt, the readiness check does its own retries.
+/// Bounds a probe in agent mode like the ssh connect timeout does.
+const AGENT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const SSH_PROBE_ARGUMENTS: [&str; 6] = [
+    "-o",
+    "ConnectTimeout=5",
     "-o",
-    "ConnectTimeout=60",
+    "ConnectionAttempts=1",
     "-o",
-    "ConnectionAttempts=60",
+    "BatchMode=yes",
 ];
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -46,41 +71,247 @@ pub enum Error {
     AlreadyRunning(String),
     #[error("Cannot create VM XML: {0}")]
     VmXml(#[source] IoError),
+    #[error("{0}")]
+    Domain(#[from] DomainError),
     #[error("Cannot remove old VM data ({0}): {1}")]
     Cleanup(String, #[source] IoError),
     #[error("Cannot create image from base: {0}")]
     CreateImage(String),
     #[error("Cannot create VM: {0}")]
     CreateVm(String),
-    #[error("VM \"{0}\" ready check failed: {1}")]
-    VmReadyCheck(String, String),
+    #[error("Cannot copy files into VM image: {0}")]
+    CopyIn(String),
+    #[error("{0}")]
+    VmReadyCheck(Box<ReadyFailure>),
     #[error("Cannot clone log file descriptor: {0}")]
     LogFileDescriptor(#[source] IoError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("VM \"{0}\" doesn't have an IP address")]
+    NoAddress(String),
+    #[error("Guest agent error: {0}")]
+    Agent(#[from] AgentError),
+    #[error("Cannot create local artifact file: {0}")]
+    Artifact(#[source] IoError),
+    #[error("Cannot list artifacts: {0}")]
+    ArtifactList(String),
+    #[error("Cannot copy artifact: {0}")]
+    ArtifactCopy(String),
+    #[error("Cannot create backtraces: {0}")]
+    Backtrace(String),
+    #[error("Cannot get domain state: {0}")]
+    DomainState(String),
+    #[error("Cannot save keep marker: {0}")]
+    Keep(#[source] IoError),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReadyStage {
+    DomainRunning,
+    Address,
+    Responding,
+    SystemRunning,
+    Ready,
+}
+
+impl ReadyStage {
+    fn next(self, exec: ExecMode, ready: &Ready) -> Self {
+        match self {
+            ReadyStage::DomainRunning if exec == ExecMode::Agent => ReadyStage::Responding,
+            ReadyStage::DomainRunning => ReadyStage::Address,
+            ReadyStage::Address => ReadyStage::Responding,
+            ReadyStage::Responding if ready.system_running() => ReadyStage::SystemRunning,
+            ReadyStage::Responding | ReadyStage::SystemRunning | ReadyStage::Ready => {
+                ReadyStage::Ready
+            }
+        }
+    }
+}
+
+impl Display for ReadyStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let stage = match self {
+            ReadyStage::DomainRunning => "domain running",
+            ReadyStage::Address => "DHCP lease",
+            ReadyStage::Responding => "guest responding",
+            ReadyStage::SystemRunning => "system running",
+            ReadyStage::Ready => "ready",
+        };
+        write!(f, "{stage}")
+    }
+}
+
+/// Diagnostics attached to a failed readiness check.
+#[derive(Debug)]
+pub struct ReadyFailure {
+    name: String,
+    stage: ReadyStage,
+    reason: String,
+    state: String,
+    console: String,
+}
+
+impl Display for ReadyFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "VM \"{}\" ready check failed ({}): {}\nDomain state: {}\nConsole tail:\n{}",
+            self.name, self.stage, self.reason, self.state, self.console
+        )
+    }
+}
+
+enum Probe {
+    Done,
+    Pending(String),
+    Failed(String),
+}
+
+/// Result of the artifact collection, paths are relative to the job
+/// log directory.
+#[derive(Debug, Default)]
+pub struct Artifacts {
+    collected: Vec<PathBuf>,
+    missing: Vec<String>,
+    backtrace: Option<PathBuf>,
+}
+
+impl Artifacts {
+    pub fn collected(&self) -> &[PathBuf] {
+        &self.collected
+    }
+
+    pub fn backtrace(&self) -> Option<&Path> {
+        self.backtrace.as_deref()
+    }
+
+    pub fn set_backtrace(&mut self, backtrace: Option<PathBuf>) {
+        self.backtrace = backtrace;
+    }
+
+    /// Adds artifacts of another VM whose log directory is `dir`
+    /// within the job log directory.
+    pub fn merge(&mut self, other: Artifacts, dir: &Path) {
+        self.collected
+            .extend(other.collected.into_iter().map(|path| dir.join(path)));
+    }
+
+    fn write_manifest(&self, log_path: &Path) -> std::io::Result<()> {
+        let mut manifest = String::new();
+
+        for path in &self.collected {
+            manifest.push_str(&format!("collected: {}\n", path.to_string_lossy()));
+        }
+
+        for pattern in &self.missing {
+            manifest.push_str(&format!("missing: {pattern}\n"));
+        }
+
+        fs::write(log_path.join(ARTIFACTS_MANIFEST), manifest)
+    }
+}
+
+#[derive(Debug)]
+pub enum Process {
+    Ssh(Child),
+    Agent(AgentProcess),
+}
+
+impl Process {
+    pub fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        match self {
+            Process::Ssh(child) => child.try_wait(),
+            Process::Agent(process) => process.try_wait().map_err(IoError::other),
+        }
+    }
+
+    pub fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        match self {
+            Process::Ssh(child) => child.wait(),
+            Process::Agent(process) => process.wait().map_err(IoError::other),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Vm {
     memory: u32,
     vcpu: usize,
+    ready: Ready,
+    base_image: String,
     image: String,
     name: String,
     log_path: String,
     arch: Arch,
-    net_suffix: usize,
+    slot: Slot,
+    exec: ExecMode,
+    agent: Agent,
+    ip: Option<Ipv4Addr>,
+    kept: bool,
+    profile: Option<Profile>,
+    networks: Vec<String>,
+    copy_in: Vec<(String, String)>,
 }
 
 impl Vm {
-    pub fn new<S: AsRef<str>>(index: usize, memory: u32, vcpu: usize, log_path: S) -> Self {
-        let name = format!("{VM_PREFIX}{index}");
+    pub fn new<S: AsRef<str>>(
+        slot: Slot,
+        config: &VmConfig,
+        base_image: String,
+        vcpu: usize,
+        profile: Option<&Profile>,
+        log_path: S,
+    ) -> Self {
+        let name = slot.name();
         Vm {
-            memory,
+            memory: profile
+                .and_then(|profile| profile.memory())
+                .unwrap_or(config.memory()),
             vcpu,
+            ready: config.ready().clone(),
+            base_image,
+            exec: config.exec(),
+            agent: Agent::new(&name),
             name: name.clone(),
             image: format!("{LIB_PATH}/{name}.qcow2"),
             log_path: log_path.as_ref().to_string(),
             arch: Arch::get(),
-            net_suffix: index + NET_SUFFIX_OFFSET,
+            slot,
+            ip: None,
+            kept: false,
+            profile: profile.cloned(),
+            networks: Vec::new(),
+            copy_in: Vec::new(),
+        }
+    }
+
+    /// Copies `local` into `guest_dir` of the VM image before boot,
+    /// replacing the guest entry of the same name.
+    pub fn copy_in(&mut self, local: &str, guest_dir: &str) {
+        self.copy_in
+            .push((local.to_string(), guest_dir.to_string()));
+    }
+
+    /// Adds NIC on the additional network, in the guest it follows
+    /// the management NIC in the order of the calls.
+    pub fn add_network(&mut self, network: &str) {
+        self.networks.push(network.to_string());
+    }
+
+    /// Management IP of the running VM.
+    pub fn ip(&mut self) -> Result<Ipv4Addr> {
+        if let Some(ip) = self.ip {
+            return Ok(ip);
         }
+
+        let address = self.slot.address();
+        let ip = match address.lease_ip()? {
+            Some(ip) => Some(ip),
+            None => address.agent_ip(&self.name)?,
+        };
+
+        self.ip = ip;
+        ip.ok_or_else(|| Error::NoAddress(self.name.clone()))
     }
 
     pub fn start(&mut self) -> Result<()> {
@@ -88,25 +319,18 @@ impl Vm {
             return Err(Error::AlreadyRunning(self.name.clone()));
         }
 
-        let base_image = format!("{LIB_PATH}/{BASE_IMAGE}");
         let xml_path = format!("{LIB_PATH}/{}.xml", &self.name);
         let nvram_path = format!("{LIB_PATH}/{}_VARS.fd", &self.name);
 
         let cleanup_paths = [xml_path.as_str(), nvram_path.as_str(), self.image.as_str()];
         Vm::pre_run_cleanup(&cleanup_paths)?;
 
-        let vm_xml = VM_XML
-            .replace("@VM_NAME@", &self.name)
-            .replace("@MEMSIZE@", &self.memory.to_string())
-            .replace("@VCPU_NUM@", &self.vcpu.to_string())
-            .replace("@ARCH@", self.arch.target())
-            .replace("@MACHINE@", self.arch.machine())
-            .replace("@ROOTDISK@", &self.image)
-            .replace("@MAC_SUFFIX@", &format!("{:02x}", self.net_suffix))
-            .replace("@UEFI_CODE@", UEFI_CODE)
-            .replace("@UEFI_VARS@", UEFI_VARS)
-            .replace("@NVRAM_PATH@", &nvram_path)
-            .replace("@LOG_PATH@", &format!("{}/vm.log", &self.log_path));
+        // Back the overlay by the resolved version, so that it isn't
+        // affected when the base image symlink is swapped.
+        let base_image = fs::canonicalize(&self.base_image)
+            .map_err(|e| Error::CreateImage(format!("{}: {e}", self.base_image)))?;
+
+        let vm_xml = self.domain(&nvram_path).to_xml()?;
 
         fs::write(&xml_path, vm_xml).map_err(Error::VmXml)?;
 
@@ -124,6 +348,10 @@ impl Vm {
             .status_ok()
             .map_err(Error::CreateImage)?;
 
+        if !self.copy_in.is_empty() {
+            self.customize_image()?;
+        }
+
         Command::new("virsh")
             .arg("create")
             .arg(&xml_path)
@@ -137,33 +365,379 @@ impl Vm {
         Ok(())
     }
 
+    fn customize_image(&self) -> Result<()> {
+        let mut command = Command::new("virt-customize");
+        command.arg("-a").arg(&self.image);
+
+        for (local, guest_dir) in &self.copy_in {
+            let name = Path::new(local)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            command
+                .arg("--delete")
+                .arg(format!("{guest_dir}/{name}"))
+                .arg("--copy-in")
+                .arg(format!("{local}:{guest_dir}"));
+        }
+
+        command
+            .output()
+            .map_err(|e| Error::Command("virt-customize", e))?
+            .status_ok()
+            .map_err(Error::CopyIn)
+    }
+
+    fn domain(&self, nvram_path: &str) -> Domain {
+        let mut domain = Domain::new(&self.name, self.memory, self.vcpu)
+            .os(self.arch.target(), self.arch.machine())
+            .uefi(UEFI_CODE, UEFI_VARS, nvram_path)
+            .disk(Disk::new(&self.image).serial("ovn-ci-root-disk"))
+            .interface(Interface::network(NETWORK_NAME, &self.slot.address().mac()))
+            .serial(&format!("{}/vm.log", &self.log_path));
+
+        for (index, network) in self.networks.iter().enumerate() {
+            domain = domain.interface(Interface::network(
+                network,
+                &self.slot.address().network_mac(index),
+            ));
+        }
+
+        let Some(profile) = &self.profile else {
+            return domain;
+        };
+
+        if profile.hugepages() {
+            domain = domain.hugepages(profile.hugepage_size());
+        }
+
+        // Pins the vCPUs 1:1 when the cpuset is big enough, otherwise
+        // every vCPU floats over the whole cpuset.
+        let cpus = profile.cpus();
+        if !cpus.is_empty() {
+            let cpuset = cpus
+                .iter()
+                .map(|cpu| cpu.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+
+            for vcpu in 0..self.vcpu {
+                let pin = match cpus.get(vcpu) {
+                    Some(cpu) if cpus.len() >= self.vcpu => cpu.to_string(),
+                    _ => cpuset.clone(),
+                };
+                domain = domain.vcpu_pin(vcpu, &pin);
+            }
+        }
+
+        if let Some(node) = profile.numa_node() {
+            domain = domain.numa_node(node);
+        }
+
+        let mut cpu = match profile.cpu_model() {
+            Some(model) => Cpu::new(CpuModel::Custom(model.to_string())),
+            None => Cpu::default(),
+        };
+        if let Some(topology) = profile.topology() {
+            cpu = cpu.topology(topology.sockets(), topology.cores(), topology.threads());
+        }
+        domain = domain.cpu(cpu);
+
+        for disk in profile.disks() {
+            let mut extra = Disk::new(disk.path());
+            if disk.readonly() {
+                extra = extra.readonly();
+            }
+            if let Some(format) = disk.format() {
+                extra = extra.format(format);
+            }
+            domain = domain.disk(extra);
+        }
+
+        profile
+            .qemu_args()
+            .iter()
+            .fold(domain, |domain, arg| domain.qemu_arg(arg))
+    }
+
     pub fn command_output(&mut self, command: &mut Command) -> Result<Output> {
-        let mut ssh = self.ssh(command);
-        ssh.output().map_err(|e| Error::Command("ssh", e))
+        match self.exec {
+            ExecMode::Ssh => {
+                let mut ssh = self.ssh(command)?;
+                ssh.output().map_err(|e| Error::Command("ssh", e))
+            }
+            ExecMode::Agent => Ok(self.agent.output(command, None)?),
+        }
     }
 
-    pub fn command_spawn(&mut self, command: &mut Command, log: File) -> Result<Child> {
+    pub fn command_spawn(&mut self, command: &mut Command, log: File) -> Result<Process> {
+        if self.exec == ExecMode::Agent {
+            let process = AgentProcess::spawn(self.agent.clone(), command, GUEST_LOG, log)?;
+            return Ok(Process::Agent(process));
+        }
+
         let clone = log.try_clone().map_err(Error::LogFileDescriptor)?;
 
-        self.ssh(command)
+        self.ssh(command)?
             .stdout(log)
             .stderr(clone)
             .spawn()
+            .map(Process::Ssh)
             .map_err(|e| Error::Command("ssh", e))
     }
 
-    pub fn retreive_artifacts(&mut self) -> Result<()> {
-        Command::new("scp")
-            .args(SSH_COMMON_ARGUMENTS)
-            .arg(format!(
-                "root@192.168.100.{}:/root/logs.tgz",
-                self.net_suffix
-            ))
-            .arg(&self.log_path)
+    /// Single short probe whether the guest responds to commands.
+    pub fn is_reachable(&mut self) -> bool {
+        matches!(self.probe_responding(), Probe::Done)
+    }
+
+    /// Copies the artifacts matching `patterns` into the log directory.
+    /// Patterns that cannot be resolved because the guest doesn't respond
+    /// are read from the overlay disk with libguestfs after the VM is
+    /// destroyed.
+    pub fn retrieve_artifacts(&mut self, patterns: &[String]) -> Artifacts {
+        let root = PathBuf::from(&self.log_path).join(ARTIFACTS_DIR);
+        let mut artifacts = Artifacts::default();
+        let mut offline = Vec::new();
+
+        // Every guest command of a dead guest would wait out the ssh
+        // connect attempts, skip straight to the offline copy.
+        let reachable = self.is_reachable();
+        if !reachable {
+            eprintln!("Guest {} unreachable, copying artifacts offline", self.name);
+            offline.extend(patterns);
+        }
+
+        for pattern in patterns.iter().filter(|_| reachable) {
+            let matches = match self.expand_artifact(pattern) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    eprintln!("Guest {} unreachable for \"{pattern}\": {e}", self.name);
+                    offline.push(pattern);
+                    continue;
+                }
+            };
+
+            if matches.is_empty() {
+                artifacts.missing.push(pattern.clone());
+            }
+
+            for (path, is_dir) in matches {
+                match self.copy_out_artifact(&path, is_dir, &root) {
+                    Ok(local) => artifacts.collected.push(local),
+                    Err(e) => {
+                        eprintln!("Couldn't copy artifact {path} from {}: {e}", self.name);
+                        artifacts.missing.push(path);
+                    }
+                }
+            }
+        }
+
+        if !offline.is_empty() {
+            self.destroy();
+        }
+
+        for pattern in offline {
+            match self.copy_out_offline(pattern, &root) {
+                Ok(collected) if !collected.is_empty() => artifacts.collected.extend(collected),
+                Ok(_) => artifacts.missing.push(pattern.clone()),
+                Err(e) => {
+                    eprintln!("Couldn't copy artifact {pattern} from {}: {e}", self.image);
+                    artifacts.missing.push(pattern.clone());
+                }
+            }
+        }
+
+        if let Err(e) = artifacts.write_manifest(Path::new(&self.log_path)) {
+            eprintln!("Couldn't write artifacts manifest: {e}");
+        }
+
+        artifacts
+    }
+
+    /// Produces "gdb -batch" backtraces for every core stored by the
+    /// core_pattern helper. Returns the summary path relative to the job
+    /// log directory, `None` when there are no cores.
+    pub fn collect_backtraces(&mut self) -> Result<Option<PathBuf>> {
+        let mut gdb = Command::new("sh");
+        gdb.arg("-c").arg(format!(
+            r#"for core in {GUEST_CORE_DIR}/core.*; do [ -e "$core" ] || continue; name=${{core#{GUEST_CORE_DIR}/core.}}; echo "=== $name"; gdb -batch -ex 'info sharedlibrary' -ex 'thread apply all bt' "{GUEST_CORE_DIR}/exe.$name" "$core" 2>&1; done; exit 0"#
+        ));
+
+        let summary = self
+            .command_output(&mut gdb)?
+            .stdout()
+            .map_err(|e| Error::Backtrace(e.trim_end().to_string()))?;
+
+        if summary.is_empty() {
+            return Ok(None);
+        }
+
+        let path = PathBuf::from(&self.log_path).join(BACKTRACE_FILE);
+        fs::write(&path, summary).map_err(Error::Artifact)?;
+
+        Ok(Some(self.relative_path(&path)))
+    }
+
+    /// Resolves the pattern in the guest, returns the matches with
+    /// a flag whether the match is a directory.
+    fn expand_artifact(&mut self, pattern: &str) -> Result<Vec<(String, bool)>> {
+        let mut expand = Command::new("sh");
+        expand.arg("-c").arg(format!(
+            r#"for f in {pattern}; do if [ -d "$f" ]; then echo "d $f"; elif [ -e "$f" ]; then echo "f $f"; fi; done; exit 0"#
+        ));
+
+        let stdout = self
+            .command_output(&mut expand)?
+            .stdout()
+            .map_err(|e| Error::ArtifactList(e.trim_end().to_string()))?;
+
+        Ok(stdout
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(kind, path)| (path.to_string(), kind == "d"))
+            .collect())
+    }
+
+    fn copy_out_artifact(&mut self, path: &str, is_dir: bool, root: &Path) -> Result<PathBuf> {
+        let guest_path = Path::new(path);
+        // The default archive stays where it always was, in the job directory.
+        let local_dir = if path == DEFAULT_ARTIFACT {
+            PathBuf::from(&self.log_path)
+        } else {
+            root.join(
+                guest_path
+                    .parent()
+                    .and_then(|parent| parent.strip_prefix("/").ok())
+                    .unwrap_or(Path::new("")),
+            )
+        };
+        let name = guest_path.file_name().unwrap_or_default().to_string_lossy();
+        DirBuilder::new()
+            .recursive(true)
+            .create(&local_dir)
+            .map_err(Error::Artifact)?;
+
+        let local = match self.exec {
+            ExecMode::Ssh => {
+                Command::new("scp")
+                    .args(SSH_COMMON_ARGUMENTS)
+                    .args(SSH_CONNECT_ARGUMENTS)
+                    .arg("-r")
+                    .arg(format!("{}:{path}", self.ssh_target()?))
+                    .arg(&local_dir)
+                    .output()
+                    .map_err(|e| Error::Command("scp", e))?
+                    .status_ok()
+                    .map_err(Error::ArtifactCopy)?;
+
+                local_dir.join(name.as_ref())
+            }
+            ExecMode::Agent if is_dir => {
+                let mut tar = Command::new("tar");
+                tar.arg("-czf")
+                    .arg(GUEST_ARCHIVE)
+                    .arg("-C")
+                    .arg(guest_path.parent().unwrap_or(Path::new("/")))
+                    .arg(name.as_ref());
+                self.command_output(&mut tar)?
+                    .status_ok()
+                    .map_err(Error::ArtifactCopy)?;
+
+                let local = local_dir.join(format!("{name}.tgz"));
+                let mut file = File::create(&local).map_err(Error::Artifact)?;
+                self.agent.read_file(GUEST_ARCHIVE, &mut file)?;
+
+                local
+            }
+            ExecMode::Agent => {
+                let local = local_dir.join(name.as_ref());
+                let mut file = File::create(&local).map_err(Error::Artifact)?;
+                self.agent.read_file(path, &mut file)?;
+
+                local
+            }
+        };
+
+        Ok(self.relative_path(&local))
+    }
+
+    /// Copies the matches of the pattern into its own directory, files
+    /// of the same name matched by different patterns are kept apart.
+    fn copy_out_offline(&self, pattern: &str, root: &Path) -> Result<Vec<PathBuf>> {
+        if pattern == DEFAULT_ARTIFACT {
+            self.guestfish_copy_out(pattern, Path::new(&self.log_path))?;
+
+            let local = Path::new(&self.log_path)
+                .join(Path::new(DEFAULT_ARTIFACT).file_name().unwrap_or_default());
+            return Ok(local
+                .exists()
+                .then(|| self.relative_path(&local))
+                .into_iter()
+                .collect());
+        }
+
+        let dir = root.join(
+            pattern
+                .trim_start_matches('/')
+                .replace(['/', '*', '?', '[', ']'], "_"),
+        );
+        DirBuilder::new()
+            .recursive(true)
+            .create(&dir)
+            .map_err(Error::Artifact)?;
+        self.guestfish_copy_out(pattern, &dir)?;
+
+        let mut collected = list_dir(&dir)?
+            .iter()
+            .map(|local| self.relative_path(local))
+            .collect::<Vec<_>>();
+        collected.sort();
+
+        Ok(collected)
+    }
+
+    fn guestfish_copy_out(&self, pattern: &str, dir: &Path) -> Result<()> {
+        Command::new("guestfish")
+            .arg("--ro")
+            .arg("-a")
+            .arg(&self.image)
+            .arg("-i")
+            .arg("glob")
+            .arg("copy-out")
+            .arg(pattern)
+            .arg(dir)
             .output()
-            .map_err(|e| Error::Command("virt-copy-out", e))?;
+            .map_err(|e| Error::Command("guestfish", e))?
+            .status_ok()
+            .map_err(Error::ArtifactCopy)
+    }
 
-        Ok(())
+    fn relative_path(&self, local: &Path) -> PathBuf {
+        local
+            .strip_prefix(&self.log_path)
+            .unwrap_or(local)
+            .to_path_buf()
+    }
+
+    /// Leaves the VM running for debugging after the runner is done,
+    /// as long as the keep budget of the slot pool allows it.
+    pub fn keep(&mut self, ttl: Duration) -> Result<Option<KeptVm>> {
+        if !self.slot.can_retain() || !self.is_running()? {
+            return Ok(None);
+        }
+
+        let endpoint = match self.exec {
+            ExecMode::Ssh => format!("ssh -i {SSH_KEY} {}", self.ssh_target()?),
+            ExecMode::Agent => format!("virsh console {}", self.name),
+        };
+
+        let kept = KeptVm::new(&self.name, &endpoint, &self.log_path, ttl);
+        kept.save().map_err(Error::Keep)?;
+        self.slot.retain();
+        self.kept = true;
+
+        Ok(Some(kept))
     }
 
     pub fn destroy(&mut self) {
@@ -189,36 +763,192 @@ impl Vm {
         Ok(stdout.lines().any(|line| line == self.name))
     }
 
+    /// Walks through the readiness stages until the guest is usable,
+    /// probing each stage with exponential backoff up to the deadline.
     fn wait_start(&mut self) -> Result<()> {
+        let deadline = Instant::now() + self.ready.timeout();
+        let mut backoff = self.ready.backoff();
+        let mut stage = ReadyStage::DomainRunning;
+
+        loop {
+            let reason = match self.probe(stage) {
+                Probe::Done => {
+                    stage = stage.next(self.exec, &self.ready);
+                    backoff = self.ready.backoff();
+
+                    if stage == ReadyStage::Ready {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Probe::Failed(reason) => return Err(self.ready_error(stage, reason)),
+                Probe::Pending(reason) => reason,
+            };
+
+            let now = Instant::now();
+            if now >= deadline {
+                let reason = format!("{reason} (timed out after {:?})", self.ready.timeout());
+                return Err(self.ready_error(stage, reason));
+            }
+
+            thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(self.ready.max_backoff());
+        }
+    }
+
+    fn probe(&mut self, stage: ReadyStage) -> Probe {
+        match stage {
+            ReadyStage::DomainRunning => match self.domain_state() {
+                Ok(state) if state.starts_with("running") => Probe::Done,
+                Ok(state) if state.starts_with("shut off") || state.starts_with("crashed") => {
+                    Probe::Failed(format!("domain is {state}"))
+                }
+                Ok(state) => Probe::Pending(format!("domain is {state}")),
+                // Transient domains disappear as soon as they stop.
+                Err(e) => Probe::Failed(e.to_string()),
+            },
+            ReadyStage::Address => {
+                let address = self.slot.address();
+                let ip = address
+                    .lease_ip()
+                    .transpose()
+                    .or_else(|| address.agent_ip(&self.name).transpose());
+
+                match ip {
+                    Some(Ok(ip)) => {
+                        self.ip = Some(ip);
+                        Probe::Done
+                    }
+                    Some(Err(e)) => Probe::Pending(e.to_string()),
+                    None => Probe::Pending(format!("no DHCP lease for {}", address.mac())),
+                }
+            }
+            ReadyStage::Responding => self.probe_responding(),
+            ReadyStage::SystemRunning => {
+                let mut systemctl = Command::new("systemctl");
+                systemctl.arg("is-system-running");
+
+                // The command exits non-zero for anything but "running".
+                match self.probe_output(&mut systemctl) {
+                    Ok(output) => match String::from_utf8_lossy(&output.stdout).trim() {
+                        "running" => Probe::Done,
+                        // A failed unit unrelated to the tests shouldn't fail the job.
+                        "degraded" => {
+                            self.record_failed_units();
+                            Probe::Done
+                        }
+                        state @ ("maintenance" | "stopping") => {
+                            Probe::Failed(format!("system is {state}"))
+                        }
+                        state => Probe::Pending(format!("system is {state}")),
+                    },
+                    Err(e) => Probe::Pending(e.to_string()),
+                }
+            }
+            ReadyStage::Ready => Probe::Done,
+        }
+    }
+
+    fn record_failed_units(&mut self) {
+        let mut systemctl = Command::new("systemctl");
+        systemctl.arg("--failed").arg("--no-legend").arg("--plain");
+
+        let units = match self.probe_output(&mut systemctl) {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+            Err(e) => format!("unavailable ({e})\n"),
+        };
+
+        eprintln!("VM \"{}\" is degraded, failed units:\n{units}", self.name);
+        if let Err(e) = fs::write(format!("{}/{FAILED_UNITS_FILE}", self.log_path), units) {
+            eprintln!("Couldn't save failed units of VM \"{}\": {e}", self.name);
+        }
+    }
+
+    fn probe_responding(&mut self) -> Probe {
+        if self.exec == ExecMode::Agent
+            && let Err(e) = self.agent.ping()
+        {
+            return Probe::Pending(e.to_string());
+        }
+
         let mut echo = Command::new("echo");
         echo.arg(READY_STRING);
 
-        let output = self
-            .command_output(&mut echo)?
-            .stdout()
-            .map_err(|e| Error::VmReadyCheck(self.name.clone(), e))?;
+        match self.probe_output(&mut echo).map(|output| output.stdout()) {
+            Ok(Ok(output)) if output.trim_end() == READY_STRING => Probe::Done,
+            Ok(Ok(_)) => Probe::Pending("the ready string didn't match".to_string()),
+            Ok(Err(e)) => Probe::Pending(e.trim_end().to_string()),
+            Err(e) => Probe::Pending(e.to_string()),
+        }
+    }
 
-        if output.trim_end() == READY_STRING {
-            Ok(())
-        } else {
-            Err(Error::VmReadyCheck(
-                self.name.clone(),
-                "The ready string didn't match!".to_string(),
-            ))
+    fn probe_output(&mut self, command: &mut Command) -> Result<Output> {
+        match self.exec {
+            ExecMode::Ssh => self
+                .ssh_with(command, &SSH_PROBE_ARGUMENTS)?
+                .output()
+                .map_err(|e| Error::Command("ssh", e)),
+            ExecMode::Agent => Ok(self
+                .agent
+                .output(command, Some(Instant::now() + AGENT_PROBE_TIMEOUT))?),
         }
     }
 
-    fn ssh(&mut self, command: &mut Command) -> Command {
+    fn domain_state(&self) -> Result<String> {
+        let stdout = Command::new("virsh")
+            .arg("domstate")
+            .arg("--reason")
+            .arg(&self.name)
+            .output()
+            .map_err(|e| Error::Command("virsh-domstate", e))?
+            .stdout()
+            .map_err(|e| Error::DomainState(e.trim_end().to_string()))?;
+
+        Ok(stdout.trim().to_string())
+    }
+
+    fn ready_error(&self, stage: ReadyStage, reason: String) -> Error {
+        let state = self
+            .domain_state()
+            .unwrap_or_else(|e| format!("unknown ({e})"));
+        let console = match fs::read(format!("{}/vm.log", self.log_path)) {
+            Ok(log) => {
+                let log = String::from_utf8_lossy(&log);
+                let lines = log.lines().collect::<Vec<_>>();
+                lines[lines.len().saturating_sub(CONSOLE_TAIL_LINES)..].join("\n")
+            }
+            Err(e) => format!("unavailable ({e})"),
+        };
+
+        Error::VmReadyCheck(Box::new(ReadyFailure {
+            name: self.name.clone(),
+            stage,
+            reason,
+            state,
+            console,
+        }))
+    }
+
+    fn ssh_target(&self) -> Result<String> {
+        self.ip
+            .map(|ip| format!("root@{ip}"))
+            .ok_or_else(|| Error::NoAddress(self.name.clone()))
+    }
+
+    fn ssh(&mut self, command: &mut Command) -> Result<Command> {
+        self.ssh_with(command, &SSH_CONNECT_ARGUMENTS)
+    }
+
+    fn ssh_with(&mut self, command: &mut Command, connect: &[&str]) -> Result<Command> {
         let mut ssh = Command::new("ssh");
 
         ssh.args(SSH_COMMON_ARGUMENTS)
-            .arg(format!("root@192.168.100.{}", self.net_suffix));
+            .args(connect)
+            .arg(self.ssh_target()?);
 
-        ssh.args(command.get_envs().map(map_envs));
-        ssh.arg(command.get_program());
-        ssh.args(command.get_args());
+        ssh.arg(shell_command(command));
 
-        ssh
+        Ok(ssh)
     }
 
     fn pre_run_cleanup(paths: &[&str]) -> Result<()> {
@@ -233,14 +963,15 @@ impl Vm {
 
 impl Drop for Vm {
     fn drop(&mut self) {
-        self.destroy();
+        if !self.kept {
+            self.destroy();
+        }
     }
 }
 
-pub fn map_envs(pair: (&OsStr, Option<&OsStr>)) -> String {
-    format!(
-        "export {}={};",
-        pair.0.to_string_lossy(),
-        pair.1.unwrap_or(OsStr::new("")).to_string_lossy()
-    )
+fn list_dir(path: &Path) -> Result<HashSet<PathBuf>> {
+    fs::read_dir(path)
+        .map_err(Error::Artifact)?
+        .map(|entry| entry.map(|entry| entry.path()).map_err(Error::Artifact))
+        .collect()
 }
@@ -0,0 +1,106 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::vm::network::{Address, AddressPool};
+
+pub const VM_PREFIX: &str = "ovn-ci-vm";
+
+/// Fixed set of VM slots, each slot owns its name, disk paths and
+/// address for the whole run. Runners lease a slot when they start
+/// and return it when they are finished.
+#[derive(Debug, Clone)]
+pub struct SlotPool {
+    addresses: Rc<Vec<Address>>,
+    used: Rc<RefCell<Vec<bool>>>,
+    keep_budget: Rc<Cell<usize>>,
+}
+
+impl SlotPool {
+    /// Creates pool of `size` slots, slots whose VM is in `kept`
+    /// stay occupied by the VM that is kept from an earlier run.
+    pub fn new(
+        size: usize,
+        addresses: &AddressPool,
+        keep_budget: usize,
+        kept: &HashSet<String>,
+    ) -> Self {
+        let addresses = (0..size)
+            .map_while(|_| addresses.acquire().ok())
+            .collect::<Vec<_>>();
+        let used = (0..addresses.len())
+            .map(|index| kept.contains(&slot_name(index)))
+            .collect::<Vec<_>>();
+
+        SlotPool {
+            addresses: Rc::new(addresses),
+            used: Rc::new(RefCell::new(used)),
+            keep_budget: Rc::new(Cell::new(keep_budget)),
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.used.borrow().iter().filter(|used| !**used).count()
+    }
+
+    pub fn acquire(&self) -> Option<Slot> {
+        let mut used = self.used.borrow_mut();
+        let index = used.iter().position(|used| !used)?;
+        used[index] = true;
+
+        Some(Slot {
+            index,
+            pool: self.clone(),
+            retained: false,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Slot {
+    index: usize,
+    pool: SlotPool,
+    retained: bool,
+}
+
+impl Slot {
+    pub fn name(&self) -> String {
+        slot_name(self.index)
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.pool.addresses[self.index]
+    }
+
+    pub fn can_retain(&self) -> bool {
+        self.retained || self.pool.keep_budget.get() > 0
+    }
+
+    /// Keeps the slot occupied for the rest of the run, the VM
+    /// in this slot outlives the runner.
+    pub fn retain(&mut self) {
+        if self.retained {
+            return;
+        }
+
+        let budget = self.pool.keep_budget.get();
+        self.pool.keep_budget.set(budget.saturating_sub(1));
+        self.retained = true;
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if self.retained {
+            return;
+        }
+
+        if let Some(used) = self.pool.used.borrow_mut().get_mut(self.index) {
+            *used = false;
+        }
+    }
+}
+
+fn slot_name(index: usize) -> String {
+    format!("{VM_PREFIX}{index}")
+}
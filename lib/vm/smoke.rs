@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{Error as IoError, Write};
+use std::path::Path;
+use std::process::Command;
+
+use thiserror::Error as ThisError;
+
+use crate::util::OutputExt;
+use crate::vm::{RunnerVm, RunnerVmError, TEST_IMAGE};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("Cannot boot VM: {0}")]
+    Vm(#[from] RunnerVmError),
+    #[error("Check \"{0}\" failed: {1}")]
+    Check(&'static str, String),
+    #[error("Cannot write smoke test log: {0}")]
+    Log(#[source] IoError),
+}
+
+/// Pre-flight checks of a freshly booted VM, everything the jobs
+/// need has to work before they are started.
+pub struct SmokeTest<'a> {
+    vm: &'a mut RunnerVm,
+    min_free_space: u64,
+    log: File,
+}
+
+impl<'a> SmokeTest<'a> {
+    pub fn new(vm: &'a mut RunnerVm, min_free_space: u64, log_path: &Path) -> Result<Self> {
+        Ok(SmokeTest {
+            vm,
+            min_free_space,
+            log: File::create(log_path.join("smoke.log")).map_err(Error::Log)?,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        self.vm.start()?;
+
+        self.check("guest command", "true")?;
+        self.check("podman image", &format!("podman image exists {TEST_IMAGE}"))?;
+        self.check(
+            "gcc",
+            &format!("podman run --rm {TEST_IMAGE} gcc --version"),
+        )?;
+        self.check(
+            "clang",
+            &format!("podman run --rm {TEST_IMAGE} clang --version"),
+        )?;
+
+        let available = self
+            .check("free space", "df --output=avail -B1G / | tail -n 1")?
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| Error::Check("free space", e.to_string()))?;
+
+        if available < self.min_free_space {
+            return Err(Error::Check(
+                "free space",
+                format!(
+                    "{available} GiB available, {} GiB required",
+                    self.min_free_space
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check(&mut self, name: &'static str, script: &str) -> Result<String> {
+        let output = self
+            .vm
+            .command_output(Command::new("sh").arg("-c").arg(script))?;
+
+        writeln!(self.log, "=== {name}: {script}").map_err(Error::Log)?;
+        self.log.write_all(&output.stdout).map_err(Error::Log)?;
+        self.log.write_all(&output.stderr).map_err(Error::Log)?;
+
+        output.stdout().map_err(|e| match e.trim_end() {
+            "" => Error::Check(name, output.status.to_string()),
+            e => Error::Check(name, e.to_string()),
+        })
+    }
+}
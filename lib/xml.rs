@@ -0,0 +1,79 @@
+/// Minimal XML element writer for the generated documents.
+#[derive(Debug)]
+pub struct Element {
+    name: &'static str,
+    attributes: Vec<(&'static str, String)>,
+    text: Option<String>,
+    children: Vec<Element>,
+}
+
+impl Element {
+    pub fn new(name: &'static str) -> Self {
+        Element {
+            name,
+            attributes: Vec::new(),
+            text: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn attr<T: ToString>(mut self, name: &'static str, value: T) -> Self {
+        self.attributes.push((name, value.to_string()));
+        self
+    }
+
+    pub fn text<T: ToString>(mut self, text: T) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+
+    pub fn child(mut self, child: Element) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    pub fn children<I: IntoIterator<Item = Element>>(mut self, children: I) -> Self {
+        self.children.extend(children);
+        self
+    }
+
+    pub fn write(&self, xml: &mut String, depth: usize) {
+        let indent = "    ".repeat(depth);
+
+        xml.push_str(&indent);
+        xml.push('<');
+        xml.push_str(self.name);
+        for (name, value) in &self.attributes {
+            xml.push_str(&format!(" {name}='{}'", escape(value)));
+        }
+
+        match (&self.text, self.children.is_empty()) {
+            (Some(text), _) => {
+                xml.push_str(&format!(">{}</{}>\n", escape(text), self.name));
+            }
+            (None, true) => xml.push_str("/>\n"),
+            (None, false) => {
+                xml.push_str(">\n");
+                for child in &self.children {
+                    child.write(xml, depth + 1);
+                }
+                xml.push_str(&format!("{indent}</{}>\n", self.name));
+            }
+        }
+    }
+}
+
+/// Escapes the markup characters and drops the control characters
+/// that are not allowed in XML, e.g. terminal colors in test output.
+pub fn escape(value: &str) -> String {
+    value
+        .replace(
+            |c: char| c.is_control() && !matches!(c, '\t' | '\n' | '\r'),
+            "",
+        )
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
@@ -1,9 +1,16 @@
 use std::env;
 
 use anyhow::Result;
-use lib::{Configuration, ContinuousIntegration};
+use lib::{Configuration, ContinuousIntegration, History, HistoryFilter};
 
 const BUILD_OPTION: &str = "--build-image";
+const KEEP_OPTION: &str = "--keep-on-failure";
+const CLEAN_COMMAND: &str = "clean";
+const IMAGE_COMMAND: &str = "image";
+const ROLLBACK_COMMAND: &str = "rollback";
+const HISTORY_COMMAND: &str = "history";
+const HISTORY_USAGE: &str = "Usage: ovn-ci history [--suite <name>] [--test <name>] [--since <YYYY-MM-DD>] [--until <YYYY-MM-DD>] <config>";
+const DEFAULT_DISTRO: &str = "fedora";
 
 fn main() -> Result<()> {
     #[cfg(debug_assertions)]
@@ -13,27 +20,94 @@ fn main() -> Result<()> {
         }
     }
 
-    let mut args = env::args();
-    let args_len = args.len();
     // Skip the program name.
-    let _ = args.next();
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
 
-    anyhow::ensure!(
-        (2..=3).contains(&args_len),
-        "The CI takes only one argument with possible option \"{BUILD_OPTION}\"."
-    );
+    // A config file named like a command is run as "./<name>".
+    if args.first().map(String::as_str) == Some(CLEAN_COMMAND) {
+        anyhow::ensure!(args.len() == 1, "Usage: ovn-ci {CLEAN_COMMAND}");
 
-    if args_len == 3 {
-        let option = args.next().unwrap();
+        let kept = lib::reap_kept();
+        println!("{} VM(s) still kept for debugging.", kept.len());
+        return Ok(());
+    }
+
+    if args.first().map(String::as_str) == Some(IMAGE_COMMAND) {
         anyhow::ensure!(
-            option.as_str() == BUILD_OPTION,
-            "The CI accept only single option \"{BUILD_OPTION}\"."
+            (2..=3).contains(&args.len()) && args[1] == ROLLBACK_COMMAND,
+            "Usage: ovn-ci {IMAGE_COMMAND} {ROLLBACK_COMMAND} [<distro>]"
         );
+
+        let distro = args.get(2).map_or(DEFAULT_DISTRO, String::as_str);
+        let version = lib::rollback_image(distro)?;
+        println!("Base image rolled back to {version}.");
+        return Ok(());
     }
 
-    let config_path = args.next().unwrap();
+    if args.first().map(String::as_str) == Some(HISTORY_COMMAND) {
+        return history(&args[1..]);
+    }
+
+    let config_path = args.pop();
+    let mut build_image = false;
+    let mut keep_on_failure = false;
+
+    for option in args {
+        match option.as_str() {
+            BUILD_OPTION => build_image = true,
+            KEEP_OPTION => keep_on_failure = true,
+            _ => anyhow::bail!(
+                "Unknown option \"{option}\", the CI accepts \"{BUILD_OPTION}\" and \"{KEEP_OPTION}\"."
+            ),
+        }
+    }
+
+    let Some(config_path) = config_path else {
+        anyhow::bail!(
+            "Usage: ovn-ci [{BUILD_OPTION}] [{KEEP_OPTION}] <config> | ovn-ci {CLEAN_COMMAND} | ovn-ci {IMAGE_COMMAND} {ROLLBACK_COMMAND} [<distro>] | ovn-ci {HISTORY_COMMAND} [<filters>] <config>"
+        );
+    };
+
     let config = Configuration::from_file(config_path)?;
-    let mut ci = ContinuousIntegration::new(config, args_len == 3);
+    let mut ci = ContinuousIntegration::new(config, build_image, keep_on_failure);
     ci.run()?;
     Ok(())
 }
+
+fn history(args: &[String]) -> Result<()> {
+    let Some((config_path, options)) = args.split_last() else {
+        anyhow::bail!(HISTORY_USAGE);
+    };
+
+    let mut filter = HistoryFilter::default();
+    for option in options.chunks(2) {
+        let [name, value] = option else {
+            anyhow::bail!(HISTORY_USAGE);
+        };
+
+        filter = match name.as_str() {
+            "--suite" => filter.suite(value),
+            "--test" => filter.test(value),
+            "--since" => filter.since(value)?,
+            "--until" => filter.until(value)?,
+            _ => anyhow::bail!(HISTORY_USAGE),
+        };
+    }
+
+    let config = Configuration::from_file(config_path)?;
+    let history = History::open(&config.history_path())?;
+
+    if filter.has_test() {
+        history
+            .tests(&filter)?
+            .iter()
+            .for_each(|entry| println!("{entry}"));
+    } else {
+        history
+            .jobs(&filter)?
+            .iter()
+            .for_each(|entry| println!("{entry}"));
+    }
+
+    Ok(())
+}
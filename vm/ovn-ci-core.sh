@@ -0,0 +1,10 @@
+#!/bin/sh
+# Kernel core_pattern pipe helper: ovn-ci-core <global pid> <comm> <timestamp>
+# Stores the core together with the crashed binary, so it can be
+# inspected even if the binary lived inside of a container.
+dir=@CORE_DIR@
+name="$2.$1.$3"
+
+mkdir -p "$dir"
+cp "/proc/$1/exe" "$dir/exe.$name" 2>/dev/null
+cat > "$dir/core.$name"